@@ -0,0 +1,223 @@
+//! Access logging: a record per request, in a format log analyzers know.
+//!
+//! Unless `LOGFORMAT` is set, we log as publicfile does instead -- a line for
+//! each file we try to open -- and none of this applies.
+
+use std::io::Write;
+use std::str::FromStr;
+use std::time::{Instant, SystemTime};
+
+use crate::ascii::escape_json;
+
+/// The shapes an access log line can take.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Format {
+    /// The Common Log Format: `LOGFORMAT=clf`.
+    Common,
+    /// Common, plus referer and user agent: `LOGFORMAT=combined`.
+    Combined,
+    /// One JSON object per line: `LOGFORMAT=json`.
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Format, ()> {
+        match s {
+            "clf" | "common" => Ok(Format::Common),
+            "combined" => Ok(Format::Combined),
+            "json" => Ok(Format::Json),
+            _ => Err(()),
+        }
+    }
+}
+
+/// What we learn about a request as we serve it.
+#[derive(Debug, Default)]
+pub struct Record {
+    /// When the request began to arrive, by both clocks: one to print, one to
+    /// measure with.
+    pub started: Option<(SystemTime, Instant)>,
+    /// The request line, exactly as received.
+    pub request_line: Vec<u8>,
+    pub host: Option<Vec<u8>>,
+    /// The virtual host that served the request, once we know: the directory
+    /// `host` was mapped to, by way of any alias or default.
+    pub vhost: Option<Vec<u8>>,
+    pub referer: Option<Vec<u8>>,
+    pub user_agent: Option<Vec<u8>>,
+    /// The status code of our response, once we've begun it.
+    pub status: Option<Vec<u8>>,
+    /// Bytes of response body sent, not counting headers or chunk framing.
+    pub bytes: u64,
+}
+
+impl Record {
+    /// Starts a record for a request that's arriving now.
+    pub fn begin() -> Record {
+        Record {
+            started: Some((SystemTime::now(), Instant::now())),
+            ..Record::default()
+        }
+    }
+
+    /// Formats the record, ending with a newline, for a client at `remote`.
+    pub fn format(&self, format: Format, remote: &str) -> Vec<u8> {
+        let (started, elapsed) = match self.started {
+            Some((at, clock)) => (at, clock.elapsed()),
+            None => (SystemTime::now(), Default::default()),
+        };
+        let mut out = Vec::new();
+        if format == Format::Json {
+            let mut parts = self.request_line.splitn(3, |&b| b == b' ');
+            let mut field = |name: &str, value: Option<&[u8]>| {
+                out.extend_from_slice(b",\"");
+                out.extend_from_slice(name.as_bytes());
+                out.extend_from_slice(b"\":");
+                match value {
+                    Some(v) => escape_json(v, &mut out),
+                    None => out.extend_from_slice(b"null"),
+                }
+            };
+            field("time", Some(iso_8601(started).as_bytes()));
+            field("remote", Some(remote.as_bytes()));
+            field("host", self.host.as_deref());
+            field("vhost", self.vhost.as_deref());
+            field("method", parts.next());
+            // The request target, split into the path and the query.
+            let mut target = parts.next().map(|t| t.splitn(2, |&b| b == b'?'));
+            field("path", target.as_mut().and_then(Iterator::next));
+            field("query", target.as_mut().and_then(Iterator::next));
+            field("protocol", parts.next());
+            field("referer", self.referer.as_deref());
+            field("user_agent", self.user_agent.as_deref());
+            out[0] = b'{';
+            let _ = writeln!(
+                out,
+                ",\"status\":{},\"bytes\":{},\"duration_us\":{}}}",
+                self.status
+                    .as_deref()
+                    .map_or("null".into(), String::from_utf8_lossy),
+                self.bytes,
+                elapsed.as_micros()
+            );
+            return out;
+        }
+
+        let _ = write!(out, "{} - - [{}] ", remote, clf_time(started));
+        quote(&self.request_line, &mut out);
+        out.push(b' ');
+        out.extend_from_slice(self.status.as_deref().unwrap_or(b"-"));
+        if self.bytes == 0 {
+            out.extend_from_slice(b" -");
+        } else {
+            let _ = write!(out, " {}", self.bytes);
+        }
+        if format == Format::Combined {
+            for value in &[&self.referer, &self.user_agent] {
+                out.push(b' ');
+                quote(value.as_deref().unwrap_or(b"-"), &mut out);
+            }
+        }
+        out.push(b'\n');
+        out
+    }
+}
+
+/// Writes `value` in double quotes, escaping as Apache does so that a
+/// client can't forge log fields or lines.
+fn quote(value: &[u8], out: &mut Vec<u8>) {
+    out.push(b'"');
+    for &b in value {
+        match b {
+            b'"' | b'\\' => {
+                out.push(b'\\');
+                out.push(b);
+            }
+            0x20..=0x7E => out.push(b),
+            _ => {
+                let _ = write!(out, "\\x{:02x}", b);
+            }
+        }
+    }
+    out.push(b'"');
+}
+
+/// Splits an HTTP-date, like `Sun, 06 Nov 1994 08:49:37 GMT`, into day,
+/// month, year and time of day.
+fn date_parts(time: SystemTime) -> (String, String, String, String) {
+    let date = httpdate::fmt_http_date(time);
+    let mut parts = date.split(' ').skip(1).map(str::to_string);
+    let mut next = || parts.next().unwrap_or_default();
+    (next(), next(), next(), next())
+}
+
+/// Formats `time` as the Common Log Format does: `06/Nov/1994:08:49:37 +0000`.
+fn clf_time(time: SystemTime) -> String {
+    let (day, month, year, clock) = date_parts(time);
+    format!("{}/{}/{}:{} +0000", day, month, year, clock)
+}
+
+/// Formats `time` as ISO 8601: `1994-11-06T08:49:37Z`.
+fn iso_8601(time: SystemTime) -> String {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct",
+        "Nov", "Dec",
+    ];
+    let (day, month, year, clock) = date_parts(time);
+    let month = MONTHS.iter().position(|&m| m == month).unwrap_or(0) + 1;
+    format!("{}-{:02}-{}T{}Z", year, month, day, clock)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn record() -> Record {
+        Record {
+            started: Some((
+                UNIX_EPOCH + Duration::from_secs(784_111_777),
+                Instant::now(),
+            )),
+            request_line: b"GET /a\"b?c=d HTTP/1.1".to_vec(),
+            host: Some(b"example.com".to_vec()),
+            vhost: Some(b"example.com".to_vec()),
+            referer: None,
+            user_agent: Some(b"curl/7.88\x01".to_vec()),
+            status: Some(b"200".to_vec()),
+            bytes: 1234,
+        }
+    }
+
+    #[test]
+    fn test_common_formats() {
+        let r = record();
+        assert_eq!(
+            String::from_utf8(r.format(Format::Common, "192.0.2.1")).unwrap(),
+            "192.0.2.1 - - [06/Nov/1994:08:49:37 +0000] \
+             \"GET /a\\\"b?c=d HTTP/1.1\" 200 1234\n"
+        );
+        assert_eq!(
+            String::from_utf8(r.format(Format::Combined, "192.0.2.1")).unwrap(),
+            "192.0.2.1 - - [06/Nov/1994:08:49:37 +0000] \
+             \"GET /a\\\"b?c=d HTTP/1.1\" 200 1234 \"-\" \"curl/7.88\\x01\"\n"
+        );
+    }
+
+    #[test]
+    fn test_json_format() {
+        let line =
+            String::from_utf8(record().format(Format::Json, "::1")).unwrap();
+        assert!(line.starts_with(
+            "{\"time\":\"1994-11-06T08:49:37Z\",\"remote\":\"::1\",\
+             \"host\":\"example.com\",\"vhost\":\"example.com\",\
+             \"method\":\"GET\",\"path\":\"/a\\\"b\",\
+             \"query\":\"c=d\",\"protocol\":\"HTTP/1.1\",\"referer\":null,\
+             \"user_agent\":\"curl/7.88\\u0001\",\"status\":200,\
+             \"bytes\":1234,\"duration_us\":"
+        ));
+        assert!(line.ends_with("}\n"));
+    }
+}
@@ -0,0 +1,96 @@
+//! Host aliases, from an `.aliases` file at the top of the document root, for
+//! serving several names from one virtual host's directory without symlinks.
+//!
+//! Each line of the file names an alias, then the host it stands for:
+//!
+//! ```text
+//! # alias           host
+//! www.example.com   example.com
+//! example.net       example.com
+//! ```
+//!
+//! Names are normalized as they would be in a request, so case and ports
+//! don't matter.  An alias takes precedence over any directory of its own,
+//! and aliases of aliases aren't followed.  Lines that don't name exactly two
+//! valid hosts are ignored.
+//!
+//! The file is read the first time a connection needs it.
+
+use std::cell::RefCell;
+
+use crate::file;
+use crate::server::normalize_host;
+
+/// An alias and the host it stands for, both normalized.
+pub type Alias = (Vec<u8>, Vec<u8>);
+
+thread_local! {
+    /// Each document root's aliases, by directory.
+    static ALIASES: file::ConfigCache<Vec<Alias>> = RefCell::default();
+}
+
+/// Finds the host that `host`, already normalized, is an alias of in the
+/// document root `docroot`, if any.
+pub fn lookup(docroot: &[u8], host: &[u8]) -> Option<Vec<u8>> {
+    let aliases = file::cached(&ALIASES, docroot, || read(docroot));
+    find(&aliases, host)
+}
+
+/// Reads the aliases in the document root `docroot`.
+pub fn read(docroot: &[u8]) -> Vec<Alias> {
+    let mut rules_path = docroot.to_vec();
+    rules_path.extend_from_slice(b"/.aliases");
+    file::read_config(&rules_path)
+        .map(|contents| parse(&contents))
+        .unwrap_or_default()
+}
+
+fn parse(contents: &[u8]) -> Vec<Alias> {
+    file::config_lines(contents)
+        .filter_map(|line| {
+            let mut fields = line
+                .split(u8::is_ascii_whitespace)
+                .filter(|f| !f.is_empty());
+            let (alias, target) = (fields.next()?, fields.next()?);
+            if fields.next().is_some() {
+                return None;
+            }
+            Some((normalize_host(alias)?, normalize_host(target)?))
+        })
+        .collect()
+}
+
+/// Finds the host that `host`, already normalized, is an alias of among
+/// `aliases`, if any.
+pub fn find(aliases: &[Alias], host: &[u8]) -> Option<Vec<u8>> {
+    aliases
+        .iter()
+        .find(|(alias, _)| alias == host)
+        .map(|(_, target)| target.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find() {
+        let aliases = parse(
+            b"# alias host\r\n\
+              \n\
+              WWW.example.com  example.com\r\n\
+              example.net:80\texample.com.\n\
+              bad.example ../etc\n\
+              three.example a b\n\
+              [::1] localhost\n",
+        );
+
+        let find = |host: &[u8]| find(&aliases, host);
+        assert_eq!(find(b"www.example.com"), Some(b"example.com".to_vec()));
+        assert_eq!(find(b"example.net"), Some(b"example.com".to_vec()));
+        assert_eq!(find(b"[::1]"), Some(b"localhost".to_vec()));
+        assert_eq!(find(b"example.com"), None);
+        assert_eq!(find(b"bad.example"), None);
+        assert_eq!(find(b"three.example"), None);
+    }
+}
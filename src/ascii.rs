@@ -7,6 +7,8 @@
 //! purposes we'll pretend it's ISO-8859-1, aka the first 256 codepoints in
 //! Unicode.
 
+use std::io::Write;
+
 /// Trait for objects that can have a prefix of ASCII (or really 8-bit, e.g.
 /// ISO8859-1) characters.
 pub trait AsciiPrefix {
@@ -22,7 +24,7 @@ impl AsciiPrefix for Vec<u8> {
     }
 }
 
-impl<'a> AsciiPrefix for &'a [u8] {
+impl AsciiPrefix for &[u8] {
     fn starts_with_ignore_ascii_case(&self, prefix: &[u8]) -> bool {
         if self.len() < prefix.len() {
             false
@@ -32,9 +34,29 @@ impl<'a> AsciiPrefix for &'a [u8] {
     }
 }
 
+/// Writes `text` as a JSON string, quotes and all.  Bytes that aren't UTF-8
+/// are replaced, since JSON can't carry them.
+pub fn escape_json(text: &[u8], out: &mut Vec<u8>) {
+    out.push(b'"');
+    for c in String::from_utf8_lossy(text).chars() {
+        match c {
+            '"' => out.extend_from_slice(b"\\\""),
+            '\\' => out.extend_from_slice(b"\\\\"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => {
+                let mut buf = [0; 4];
+                out.extend_from_slice(c.encode_utf8(&mut buf).as_bytes())
+            }
+        }
+    }
+    out.push(b'"');
+}
+
 #[cfg(test)]
 mod tests {
-    use super::AsciiPrefix;
+    use super::{escape_json, AsciiPrefix};
 
     #[test]
     fn test_starts_with_ignore_ascii_case() {
@@ -43,13 +65,14 @@ mod tests {
         assert!(b"foobar".as_ref().starts_with_ignore_ascii_case(b"foo"));
         assert!(b"FOOBAR".as_ref().starts_with_ignore_ascii_case(b"foo"));
 
-        assert_eq!(
-            b"foo".as_ref().starts_with_ignore_ascii_case(b"foobar"),
-            false,
-        );
-        assert_eq!(
-            b"".as_ref().starts_with_ignore_ascii_case(b"foobar"),
-            false,
-        );
+        assert!(!b"foo".as_ref().starts_with_ignore_ascii_case(b"foobar"));
+        assert!(!b"".as_ref().starts_with_ignore_ascii_case(b"foobar"));
+    }
+
+    #[test]
+    fn test_escape_json() {
+        let mut out = Vec::new();
+        escape_json(b"q\"\\\n\xff", &mut out);
+        assert_eq!(out, "\"q\\\"\\\\\\u000a\u{fffd}\"".as_bytes());
     }
 }
@@ -0,0 +1,238 @@
+//! Directory listings, for directories without an index page.
+//!
+//! Listings are off unless `AUTOINDEX` is set.  They only show what we'd
+//! actually serve: entries that pass `file::safe_open`'s permission checks,
+//! minus dotfiles.  Clients get HTML unless they ask for JSON in `Accept`.
+
+use std::ffi;
+use std::fs;
+use std::io::Write;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::time::SystemTime;
+
+use crate::ascii::escape_json;
+use crate::con::Connection;
+use crate::error::{HttpError, Result};
+use crate::file::{self, FileOrDir};
+use crate::path;
+use crate::percent;
+use crate::request::Request;
+use crate::response;
+
+/// Sends a listing of the directory `dir`, which `req` asked for.
+pub fn send(con: &mut Connection, req: &Request, dir: &[u8]) -> Result<()> {
+    // The directory itself has to be servable, as if it were a file.
+    match file::safe_open(ffi::OsStr::from_bytes(dir)) {
+        Ok(FileOrDir::Dir) => con.log(dir, None, b"directory listing"),
+        Ok(FileOrDir::File(_)) => {
+            return Err(HttpError::NotFound(b"not a directory"))
+        }
+        Err(e) => {
+            if let Some(message) = e.log_message() {
+                con.log(dir, Some(b"listing"), &message);
+            }
+            return Err(e);
+        }
+    }
+    let entries = read_entries(dir)?;
+
+    // Show the path as we interpreted it, not as it was sent.
+    let mut shown = vec![b'/'];
+    percent::unescape(&req.path, &mut shown)?;
+    path::sanitize(&mut shown);
+
+    if req.accept_json {
+        let body = render_json(&entries);
+        response::send_generated(
+            con,
+            req,
+            b"application/json",
+            Some(b"Accept"),
+            &body,
+        )
+    } else {
+        let body = render_html(&shown, &entries);
+        response::send_generated(
+            con,
+            req,
+            b"text/html; charset=utf-8",
+            Some(b"Accept"),
+            &body,
+        )
+    }
+}
+
+/// A directory entry we're willing to admit exists.
+#[derive(Debug)]
+struct Entry {
+    name: Vec<u8>,
+    is_dir: bool,
+    length: u64,
+    mtime: SystemTime,
+}
+
+fn read_entries(dir: &[u8]) -> Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(ffi::OsStr::from_bytes(dir))? {
+        let entry = entry?;
+        let name = entry.file_name().into_vec();
+        // Names starting with a dot can't be requested, and those starting
+        // with a colon are how clients request dotfiles -- which shouldn't be
+        // advertised either.
+        if name.starts_with(b".") || name.starts_with(b":") {
+            continue;
+        }
+
+        // Follow symlinks, as opening the entry would.  Anything we can't
+        // examine, or wouldn't serve, goes unmentioned.
+        let meta = match fs::metadata(entry.path()) {
+            Ok(meta) => meta,
+            Err(_) => continue,
+        };
+        if file::check_mode(&meta).is_err()
+            || !(meta.is_file() || meta.is_dir())
+        {
+            continue;
+        }
+
+        entries.push(Entry {
+            name,
+            is_dir: meta.is_dir(),
+            length: meta.len(),
+            mtime: meta.modified()?,
+        });
+    }
+
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(entries)
+}
+
+fn render_html(shown: &[u8], entries: &[Entry]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut title = Vec::new();
+    escape_html(shown, &mut title);
+
+    out.extend_from_slice(b"<!DOCTYPE html>\n<html><head><title>Index of ");
+    out.extend_from_slice(&title);
+    out.extend_from_slice(b"</title></head><body>\n<h1>Index of ");
+    out.extend_from_slice(&title);
+    out.extend_from_slice(b"</h1>\n<table>\n");
+    if shown != b"/" {
+        out.extend_from_slice(
+            b"<tr><td><a href=\"../\">../</a></td><td></td><td></td></tr>\n",
+        );
+    }
+
+    for entry in entries {
+        out.extend_from_slice(b"<tr><td><a href=\"");
+        escape_url(&entry.name, &mut out);
+        if entry.is_dir {
+            out.push(b'/');
+        }
+        out.extend_from_slice(b"\">");
+        escape_html(&entry.name, &mut out);
+        if entry.is_dir {
+            out.extend_from_slice(b"/</a></td><td>-");
+        } else {
+            out.extend_from_slice(b"</a></td><td>");
+            let _ = write!(out, "{}", entry.length);
+        }
+        out.extend_from_slice(b"</td><td>");
+        out.extend_from_slice(httpdate::fmt_http_date(entry.mtime).as_bytes());
+        out.extend_from_slice(b"</td></tr>\n");
+    }
+
+    out.extend_from_slice(b"</table>\n</body></html>\n");
+    out
+}
+
+fn render_json(entries: &[Entry]) -> Vec<u8> {
+    let mut out = vec![b'['];
+    for (i, entry) in entries.iter().enumerate() {
+        if i > 0 {
+            out.push(b',');
+        }
+        out.extend_from_slice(b"\n{\"name\":");
+        escape_json(&entry.name, &mut out);
+        let kind = if entry.is_dir { "directory" } else { "file" };
+        let _ = write!(
+            out,
+            ",\"type\":\"{}\",\"size\":{},\"mtime\":\"{}\"}}",
+            kind,
+            entry.length,
+            httpdate::fmt_http_date(entry.mtime)
+        );
+    }
+    out.extend_from_slice(b"\n]\n");
+    out
+}
+
+/// Escapes text for HTML content or attributes.  Names that aren't UTF-8 are
+/// shown as best we can.
+fn escape_html(text: &[u8], out: &mut Vec<u8>) {
+    for c in String::from_utf8_lossy(text).chars() {
+        match c {
+            '&' => out.extend_from_slice(b"&amp;"),
+            '<' => out.extend_from_slice(b"&lt;"),
+            '>' => out.extend_from_slice(b"&gt;"),
+            '"' => out.extend_from_slice(b"&quot;"),
+            '\'' => out.extend_from_slice(b"&#39;"),
+            c => {
+                let mut buf = [0; 4];
+                out.extend_from_slice(c.encode_utf8(&mut buf).as_bytes())
+            }
+        }
+    }
+}
+
+/// Percent-escapes a name for use as a relative URL.  Only unreserved
+/// characters pass through, which also keeps a colon from being mistaken for
+/// the end of a scheme.
+fn escape_url(name: &[u8], out: &mut Vec<u8>) {
+    for &b in name {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+            out.push(b);
+        } else {
+            let _ = write!(out, "%{:02X}", b);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file::TempDir;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn test_escaping() {
+        let mut out = Vec::new();
+        escape_html(b"<a href='x'>&\"", &mut out);
+        assert_eq!(out, b"&lt;a href=&#39;x&#39;&gt;&amp;&quot;".to_vec());
+
+        out.clear();
+        escape_url(b"a b:c%/\xff.txt", &mut out);
+        assert_eq!(out, b"a%20b%3Ac%25%2F%FF.txt".to_vec());
+    }
+
+    #[test]
+    fn test_read_entries() {
+        let dir = TempDir::new("autoindex");
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        for name in &["b.txt", "a.txt", ".hidden", ":dotfile", "private"] {
+            fs::write(dir.path().join(name), b"hello").unwrap();
+        }
+        fs::set_permissions(
+            dir.path().join("private"),
+            fs::Permissions::from_mode(0o600),
+        )
+        .unwrap();
+
+        let entries = read_entries(dir.as_bytes()).unwrap();
+
+        let names: Vec<_> = entries.iter().map(|e| &e.name[..]).collect();
+        assert_eq!(names, vec![&b"a.txt"[..], b"b.txt", b"sub"]);
+        assert_eq!(entries[0].length, 5);
+        assert!(entries[2].is_dir);
+    }
+}
@@ -0,0 +1,198 @@
+//! Caching policy, from a `.cache-control` file at the top of each virtual
+//! host's directory.
+//!
+//! Each line of the file pairs a pattern with the `Cache-Control` directives
+//! for files that match it:
+//!
+//! ```text
+//! # Fingerprinted assets never change; pages always might.
+//! /assets/**   max-age=31536000, immutable
+//! *.html       no-cache
+//! ```
+//!
+//! A pattern containing a slash is matched against the whole path, from the
+//! top of the host; otherwise, against the file name alone.  `*` matches any
+//! run of characters but `/`, `**` any run at all, and `?` any one character
+//! but `/`.  The first matching line wins, passing over any with no
+//! directives, or with control characters in them.
+//!
+//! The file is read once per connection, when it's first needed.
+
+use std::cell::RefCell;
+
+use crate::file;
+
+/// What we tell caches about a file.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Policy {
+    /// The value of `Cache-Control`.
+    pub directives: Vec<u8>,
+    /// How long the file stays fresh, in seconds, if the directives say.  This
+    /// also gets expressed as `Expires`, for the benefit of HTTP/1.0 caches.
+    pub max_age: Option<u64>,
+}
+
+/// A line of the file: files matching `pattern` get `policy`.
+struct Rule {
+    pattern: Vec<u8>,
+    policy: Policy,
+}
+
+thread_local! {
+    /// Each host's rules, by directory.
+    static RULES: file::ConfigCache<Vec<Rule>> = RefCell::default();
+}
+
+/// Finds the policy for `path` in the `.cache-control` file at the top of
+/// `root`.
+pub fn lookup(root: &[u8], path: &[u8]) -> Option<Policy> {
+    let rules = file::cached(&RULES, root, || {
+        let mut rules_path = root.to_vec();
+        rules_path.extend_from_slice(b"/.cache-control");
+        file::read_config(&rules_path)
+            .map(|contents| parse(&contents))
+            .unwrap_or_default()
+    });
+    find(&rules, path)
+}
+
+/// Parses the rules in `contents`, skipping lines that aren't one.
+fn parse(contents: &[u8]) -> Vec<Rule> {
+    file::config_lines(contents)
+        .filter_map(|line| {
+            let split = line.iter().position(u8::is_ascii_whitespace)?;
+            let (pattern, directives) =
+                (&line[..split], line[split..].trim_ascii());
+            Some(Rule {
+                pattern: pattern.to_vec(),
+                policy: parse_policy(directives)?,
+            })
+        })
+        .collect()
+}
+
+fn find(rules: &[Rule], path: &[u8]) -> Option<Policy> {
+    let name =
+        &path[path.iter().rposition(|&b| b == b'/').map_or(0, |i| i + 1)..];
+
+    rules.iter().find_map(|rule| {
+        let pattern = &rule.pattern[..];
+        let subject = if pattern.contains(&b'/') { path } else { name };
+        if glob_match(pattern, subject) {
+            Some(rule.policy.clone())
+        } else {
+            None
+        }
+    })
+}
+
+/// Checks over the directives, which will be sent as they are.
+fn parse_policy(directives: &[u8]) -> Option<Policy> {
+    if directives.is_empty()
+        || directives
+            .iter()
+            .any(|&b| b != b' ' && !b.is_ascii_graphic())
+    {
+        return None;
+    }
+
+    let max_age = directives
+        .split(|&b| b == b',')
+        .map(|d| d.trim_ascii())
+        .filter(|d| d.len() > 8 && d[..8].eq_ignore_ascii_case(b"max-age="))
+        .find_map(|d| std::str::from_utf8(&d[8..]).ok()?.parse().ok());
+
+    Some(Policy {
+        directives: directives.to_vec(),
+        max_age,
+    })
+}
+
+/// Matches `text` against the glob `pattern`, as described above.
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some((b'*', rest)) => {
+            if let Some(rest) = rest.strip_prefix(b"*") {
+                (0..=text.len()).any(|i| glob_match(rest, &text[i..]))
+            } else {
+                let run =
+                    text.iter().position(|&b| b == b'/').unwrap_or(text.len());
+                (0..=run).any(|i| glob_match(rest, &text[i..]))
+            }
+        }
+        Some((b'?', rest)) => match text.split_first() {
+            Some((&c, text)) => c != b'/' && glob_match(rest, text),
+            None => false,
+        },
+        Some((&p, rest)) => match text.split_first() {
+            Some((&c, text)) => c == p && glob_match(rest, text),
+            None => false,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file::TempDir;
+    use std::fs;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match(b"*.html", b"index.html"));
+        assert!(!glob_match(b"*.html", b"index.htm"));
+        assert!(glob_match(b"/assets/*", b"/assets/app.js"));
+        assert!(!glob_match(b"/assets/*", b"/assets/js/app.js"));
+        assert!(glob_match(b"/assets/**", b"/assets/js/app.js"));
+        assert!(glob_match(b"/**/*.css", b"/a/b/site.css"));
+        assert!(glob_match(b"file?.txt", b"file1.txt"));
+        assert!(!glob_match(b"?", b"/"));
+        assert!(glob_match(b"**", b""));
+    }
+
+    #[test]
+    fn test_find() {
+        let rules = parse(
+            b"# comment\r\n\
+              \n\
+              /assets/** max-age=31536000, immutable\n\
+              *.html\tno-cache\n\
+              bogus\n\
+              *.txt  bad\x01value\n\
+              *.txt  max-age=60\n",
+        );
+
+        assert_eq!(
+            find(&rules, b"/assets/app.css"),
+            Some(Policy {
+                directives: b"max-age=31536000, immutable".to_vec(),
+                max_age: Some(31_536_000),
+            })
+        );
+        assert_eq!(
+            find(&rules, b"/docs/index.html"),
+            Some(Policy {
+                directives: b"no-cache".to_vec(),
+                max_age: None,
+            })
+        );
+        assert_eq!(find(&rules, b"/a.txt").unwrap().max_age, Some(60));
+        assert_eq!(find(&rules, b"/a.png"), None);
+    }
+
+    #[test]
+    fn test_lookup() {
+        let dir = TempDir::new("cache-control");
+        let rules_path = dir.path().join(".cache-control");
+        fs::write(&rules_path, b"* max-age=60\n").unwrap();
+
+        let first = lookup(dir.as_bytes(), b"/a.txt");
+        // Once read, the rules stick for the life of the process.
+        fs::write(&rules_path, b"* no-store\n").unwrap();
+        let second = lookup(dir.as_bytes(), b"/a.txt");
+
+        assert_eq!(first.unwrap().max_age, Some(60));
+        assert_eq!(second.unwrap().max_age, Some(60));
+    }
+}
@@ -0,0 +1,176 @@
+//! Compressing files as we serve them, for clients that accept gzip from
+//! sites that don't keep `.gz` alternates.
+//!
+//! This is off unless `COMPRESS` is set.  If `COMPRESS_CACHE` names a
+//! directory, the compressed copies are kept there, named for the file and
+//! the version of it they came from, and served just like alternates.
+
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{self, Read, Seek, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+use std::process;
+use std::time::UNIX_EPOCH;
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use crate::file::OpenFile;
+
+/// The largest file we'll compress into the cache.  Filling the cache keeps the
+/// client waiting, with no timeout to stop us, so bigger files are compressed
+/// as they're sent instead.
+pub const CACHE_LIMIT: u64 = 8 << 20;
+
+/// Gzips everything from `input` into `output`.
+pub fn gzip(mut input: impl Read, output: impl Write) -> io::Result<()> {
+    let mut encoder = GzEncoder::new(output, Compression::default());
+    io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?;
+    Ok(())
+}
+
+/// Works out where the compressed copy of `file_path`, as it was when opened
+/// as `resource`, belongs in the cache `dir`.
+pub fn cache_path(
+    dir: &[u8],
+    file_path: &[u8],
+    resource: &OpenFile,
+) -> Vec<u8> {
+    let mtime = resource
+        .mtime
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+
+    let mut path = dir.to_vec();
+    path.push(b'/');
+    path.extend_from_slice(file_path.strip_prefix(b"./").unwrap_or(file_path));
+    path.extend_from_slice(
+        format!("-{:x}-{:x}.gz", mtime.as_nanos(), resource.length).as_bytes(),
+    );
+    path
+}
+
+/// Compresses `resource` into the cache at `path`.  The copy is written under
+/// a temporary name and then moved into place, so that nobody -- including
+/// another process doing the same thing -- sees it half-done.  Copies of older
+/// versions of the file, which nobody will ask for again, are then removed.
+pub fn fill_cache(path: &[u8], resource: &mut OpenFile) -> io::Result<()> {
+    let path = Path::new(OsStr::from_bytes(path));
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(format!(".{}.tmp", process::id()));
+
+    let result = (|| {
+        let mut out = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o644)
+            .open(&temp)?;
+        resource.file.seek(io::SeekFrom::Start(0))?;
+        gzip((&mut resource.file).take(resource.length), &mut out)?;
+        // The copy has to pass the same permission checks as anything else
+        // we serve, whatever our umask thinks.
+        out.set_permissions(fs::Permissions::from_mode(0o644))?;
+        fs::rename(&temp, path)
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    } else {
+        remove_old_copies(path);
+    }
+    result
+}
+
+/// Removes the siblings of the cached copy at `path` that are copies of other
+/// versions of the same file.  This is only tidying, so failures are ignored.
+fn remove_old_copies(path: &Path) {
+    let (dir, name) = match (path.parent(), path.file_name()) {
+        (Some(dir), Some(name)) => (dir, name.as_bytes()),
+        _ => return,
+    };
+    // Cached copies are named `<file>-<mtime>-<length>.gz`.
+    let version = |name: &[u8]| {
+        let stem = name.strip_suffix(b".gz")?;
+        let mut parts = stem.rsplitn(3, |&b| b == b'-');
+        let (length, mtime, file) =
+            (parts.next()?, parts.next()?, parts.next()?);
+        let hex =
+            |s: &[u8]| !s.is_empty() && s.iter().all(u8::is_ascii_hexdigit);
+        if hex(mtime) && hex(length) {
+            Some(file.len())
+        } else {
+            None
+        }
+    };
+    let file = match version(name) {
+        Some(len) => &name[..len],
+        None => return,
+    };
+
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.flatten() {
+        let other = entry.file_name();
+        let other = other.as_bytes();
+        let same_file = version(other).is_some_and(|len| &other[..len] == file);
+        if same_file && other != name {
+            let _ = fs::remove_file(entry.path());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file::{self, FileOrDir, TempDir};
+    use flate2::read::GzDecoder;
+
+    #[test]
+    fn test_fill_cache() {
+        let dir = TempDir::new("compress");
+        let mut resource = match file::safe_open("src/main.rs") {
+            Ok(FileOrDir::File(f)) => f,
+            _ => panic!("can't open src/main.rs"),
+        };
+
+        let dir_bytes = dir.as_bytes();
+        let path = cache_path(dir_bytes, b"./src/main.rs", &resource);
+        let name = &path[dir_bytes.len()..];
+        assert!(path.starts_with(dir_bytes));
+        assert!(name.starts_with(b"/src/main.rs-"));
+        assert!(name.ends_with(format!("-{:x}.gz", resource.length).as_bytes()));
+
+        fill_cache(&path, &mut resource).unwrap();
+        let cached = match file::safe_open(OsStr::from_bytes(&path)) {
+            Ok(FileOrDir::File(f)) => f,
+            _ => panic!("cached copy isn't servable"),
+        };
+        let mut contents = Vec::new();
+        GzDecoder::new(cached.file)
+            .read_to_end(&mut contents)
+            .unwrap();
+
+        assert_eq!(contents, fs::read("src/main.rs").unwrap());
+
+        // Filling the cache again clears out older versions, but leaves alone
+        // the copies of other files and anything else.
+        let src = dir.path().join("src");
+        let others = ["main.rs-1-2.gz", "main.rs-x-1-2.gz", "main.rs-1.gz"];
+        for name in &others {
+            fs::write(src.join(name), b"").unwrap();
+        }
+        fs::remove_file(OsStr::from_bytes(&path)).unwrap();
+        fill_cache(&path, &mut resource).unwrap();
+        assert!(!src.join(others[0]).exists());
+        assert!(src.join(others[1]).exists());
+        assert!(src.join(others[2]).exists());
+        assert!(Path::new(OsStr::from_bytes(&path)).exists());
+    }
+}
@@ -1,39 +1,194 @@
 //! HTTP connection management
 
 use std::fs;
-use std::io::{self, BufRead, Write};
+use std::io::{self, BufRead, Read, Seek, Write};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
 
+use crate::access;
+use crate::config::Config;
 use crate::error::*;
 use crate::timeout;
 use crate::unix;
 
+/// Where a connection's requests come from.
+///
+/// Transports that can't wait under a timeout can ignore the limits; the
+/// connection still works, but a stalled client can hold it forever.
+pub trait Input: Read {
+    /// Changes the limit on each individual read.
+    fn set_timeout(&mut self, _timeout: Duration) {}
+
+    /// Sets (or clears) a time by which all future reads must complete.
+    fn set_deadline(&mut self, _deadline: Option<Instant>) {}
+}
+
+/// Where a connection's responses go.
+pub trait Output: Write {
+    /// Checks whether `send_file` is worth trying.
+    fn can_send_file(&self) -> bool {
+        false
+    }
+
+    /// Copies up to `count` bytes from `src`, starting at `*offset`, without
+    /// passing them through userspace.  Advances `*offset` past the bytes
+    /// copied, and returns their number, which will be zero at the end of
+    /// `src`.
+    fn send_file(
+        &mut self,
+        _src: &fs::File,
+        _offset: &mut u64,
+        _count: usize,
+    ) -> io::Result<usize> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+impl Input for io::Cursor<Vec<u8>> {}
+
+impl Output for Vec<u8> {}
+
 pub struct Connection {
-    input: io::BufReader<timeout::SafeFile>,
-    output: io::BufWriter<timeout::SafeFile>,
-    error: io::BufWriter<fs::File>,
+    input: io::BufReader<Box<dyn Input>>,
+    output: io::BufWriter<Box<dyn Output>>,
+    error: io::BufWriter<Box<dyn Write>>,
     remote: String,
+    /// Whether `write_file` should try to bypass our buffers.
+    zero_copy: bool,
+    timeouts: timeout::Timeouts,
+    /// Number of requests begun on this connection.
+    requests: usize,
+    /// How to log requests, if not in the traditional way.
+    log_format: Option<access::Format>,
+    /// What we know about the current request, for the access log.
+    record: access::Record,
+    /// Whether the client reached us over TLS.
+    secure: bool,
+    /// How to serve the client's requests.
+    config: Rc<Config>,
 }
 
 impl Connection {
-    pub fn new(remote: String) -> Connection {
+    /// Creates a connection that reads requests from `input`, sends responses
+    /// to `output`, and logs to `error`.  `remote` identifies the client in
+    /// the log.
+    pub fn new(
+        input: Box<dyn Input>,
+        output: Box<dyn Output>,
+        error: Box<dyn Write>,
+        remote: String,
+        timeouts: timeout::Timeouts,
+    ) -> Connection {
         const INPUT_BUF_BYTES: usize = 1024;
         const OUTPUT_BUF_BYTES: usize = 1024;
         const LOG_BUF_BYTES: usize = 256;
 
+        let zero_copy = output.can_send_file();
+
         Connection {
-            input: io::BufReader::with_capacity(
-                INPUT_BUF_BYTES,
-                timeout::SafeFile::new(unix::stdin()),
-            ),
-            output: io::BufWriter::with_capacity(
-                OUTPUT_BUF_BYTES,
-                timeout::SafeFile::new(unix::stdout()),
-            ),
-            error: io::BufWriter::with_capacity(LOG_BUF_BYTES, unix::stderr()),
+            input: io::BufReader::with_capacity(INPUT_BUF_BYTES, input),
+            output: io::BufWriter::with_capacity(OUTPUT_BUF_BYTES, output),
+            error: io::BufWriter::with_capacity(LOG_BUF_BYTES, error),
             remote,
+            zero_copy,
+            timeouts,
+            requests: 0,
+            log_format: None,
+            record: access::Record::default(),
+            secure: false,
+            config: Rc::default(),
         }
     }
 
+    /// Creates a connection on stdin and stdout, logging to stderr, as
+    /// arranged by `tcpserver`.
+    pub fn stdio(remote: String, timeouts: timeout::Timeouts) -> Connection {
+        Connection::new(
+            Box::new(timeout::SafeFile::new(unix::stdin(), timeouts.read)),
+            Box::new(timeout::SafeFile::new(unix::stdout(), timeouts.write)),
+            Box::new(unix::stderr()),
+            remote,
+            timeouts,
+        )
+    }
+
+    /// Returns the client's address, as given to `new`.
+    pub fn remote(&self) -> &str {
+        &self.remote
+    }
+
+    /// Switches from logging each file we open to logging each request in
+    /// `format`.
+    pub fn set_log_format(&mut self, format: Option<access::Format>) {
+        self.log_format = format;
+    }
+
+    pub fn log_format(&self) -> Option<access::Format> {
+        self.log_format
+    }
+
+    /// Notes that the client reached us over TLS, which matters when we tell
+    /// it where to go.
+    pub fn set_secure(&mut self, secure: bool) {
+        self.secure = secure;
+    }
+
+    pub fn is_secure(&self) -> bool {
+        self.secure
+    }
+
+    /// Replaces the default settings for serving requests.
+    pub fn set_config(&mut self, config: Rc<Config>) {
+        self.config = config;
+    }
+
+    pub fn config(&self) -> Rc<Config> {
+        self.config.clone()
+    }
+
+    /// Returns the access log record for the current request, for filling in.
+    pub fn record(&mut self) -> &mut access::Record {
+        &mut self.record
+    }
+
+    /// Waits for the client to start sending a request, then starts the clock
+    /// on receiving its headers.  The caller should call `end_request` once
+    /// they've arrived.
+    ///
+    /// A client that never starts its first request is timed out like any
+    /// other slow read; one that doesn't follow up on a persistent connection
+    /// has simply lost interest, and gets `ConnectionClosed`.
+    pub fn begin_request(&mut self) -> Result<()> {
+        let first = self.requests == 0;
+        let wait = if first {
+            self.timeouts.read
+        } else {
+            self.timeouts.idle
+        };
+        self.input.get_mut().set_timeout(wait);
+
+        match self.input.fill_buf() {
+            Ok([]) => return Err(HttpError::ConnectionClosed),
+            Ok(_) => (),
+            Err(ref e) if !first && e.kind() == io::ErrorKind::TimedOut => {
+                return Err(HttpError::ConnectionClosed)
+            }
+            Err(e) => return Err(e.into()),
+        }
+
+        self.requests += 1;
+        self.record = access::Record::begin();
+        let input = self.input.get_mut();
+        input.set_timeout(self.timeouts.read);
+        input.set_deadline(Some(Instant::now() + self.timeouts.request));
+        Ok(())
+    }
+
+    /// Lifts the deadline imposed by `begin_request`.
+    pub fn end_request(&mut self) {
+        self.input.get_mut().set_deadline(None);
+    }
+
     /// Reads a CRLF-terminated line, of the sort used in HTTP requests.
     /// This function guarantees that a successful result describes an entire
     /// line -- if the input is closed before CRLF, it signals
@@ -54,14 +209,23 @@ impl Connection {
             if line.last() == Some(&b'\r') {
                 line.pop();
             }
-            return Ok(line);
+            Ok(line)
         } else {
             // EOF with or without some bytes read looks the same (pop() is
             // None or not a newline).
-            return Err(HttpError::ConnectionClosed);
+            Err(HttpError::ConnectionClosed)
         }
     }
 
+    /// Fills `buf` from the client, who is expected to have that much to say:
+    /// running out of input early signals `ConnectionClosed`.
+    pub fn read_exact(&mut self, buf: &mut [u8]) -> Result<()> {
+        self.input.read_exact(buf).map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => HttpError::ConnectionClosed,
+            _ => e.into(),
+        })
+    }
+
     pub fn write(&mut self, data: &[u8]) -> Result<()> {
         // Don't use the default conversion from io::Error here -- failures on
         // write are the client's fault and can't typically be reported, so it's
@@ -71,6 +235,13 @@ impl Connection {
             .map_err(|_| HttpError::ConnectionClosed)
     }
 
+    /// Writes part of a response body, counting it for the access log.
+    pub fn write_body(&mut self, data: &[u8]) -> Result<()> {
+        self.write(data)?;
+        self.record.bytes += data.len() as u64;
+        Ok(())
+    }
+
     pub fn write_decimal(&mut self, value: usize) -> Result<()> {
         write!(self.output, "{}", value)
             .map_err(|_| HttpError::ConnectionClosed)
@@ -81,6 +252,63 @@ impl Connection {
             .map_err(|_| HttpError::ConnectionClosed)
     }
 
+    /// Copies `length` bytes of `file`, starting at `offset`, to the client.
+    /// Where possible, the data goes from the file to the client without
+    /// passing through our buffers.  Returns the number of bytes copied, which
+    /// falls short of `length` only if the file does.
+    pub fn write_file(
+        &mut self,
+        file: &mut fs::File,
+        offset: u64,
+        length: u64,
+    ) -> Result<u64> {
+        // The most we'll hand the output at once.  Each call is subject to the
+        // write timeout, so this doesn't need to be small.
+        const SEND_FILE_MAX_BYTES: u64 = 1 << 20;
+
+        if self.zero_copy {
+            // Anything we've already buffered has to go first.
+            self.flush_output()?;
+
+            let end = offset + length;
+            let mut pos = offset;
+            while pos < end {
+                let count = (end - pos).min(SEND_FILE_MAX_BYTES) as usize;
+                match self.output.get_mut().send_file(file, &mut pos, count) {
+                    Ok(0) => break,
+                    Ok(_) => (),
+                    // Some files can't be sent this way.  Find out on the
+                    // first attempt, before we've sent anything, and fall back
+                    // to copying.
+                    Err(ref e) if pos == offset && cannot_send_file(e) => {
+                        self.zero_copy = false;
+                        return self.write_file(file, offset, length);
+                    }
+                    Err(_) => return Err(HttpError::ConnectionClosed),
+                }
+            }
+            self.record.bytes += pos - offset;
+            return Ok(pos - offset);
+        }
+
+        file.seek(io::SeekFrom::Start(offset))?;
+        let mut input = io::BufReader::with_capacity(1024, file.take(length));
+        let mut copied = 0;
+        loop {
+            let count = {
+                let chunk = input.fill_buf()?;
+                if chunk.is_empty() {
+                    break;
+                }
+                self.write_body(chunk)?;
+                chunk.len()
+            };
+            input.consume(count);
+            copied += count as u64;
+        }
+        Ok(copied)
+    }
+
     pub fn flush_output(&mut self) -> Result<()> {
         self.output.flush().map_err(|_| HttpError::ConnectionClosed)
     }
@@ -91,6 +319,10 @@ impl Connection {
         context: Option<&'static [u8]>,
         msg: &[u8],
     ) {
+        if self.log_format.is_some() {
+            return;
+        }
+
         // We do not expect writes to the log to fail, and we can't easily
         // handle them if they do, so we ignore the result and return.
         let _ = (|| {
@@ -114,7 +346,18 @@ impl Connection {
         })();
     }
 
+    /// Copies an already-formatted log entry, such as one from a connection
+    /// layered on this one, to the log.
+    pub fn write_log(&mut self, entry: &[u8]) -> io::Result<()> {
+        self.error.write_all(entry)?;
+        self.error.flush()
+    }
+
     pub fn log_other(&mut self, message: &[u8]) {
+        if self.log_format.is_some() {
+            return;
+        }
+
         // We do not expect writes to the log to fail, and we can't easily
         // handle them if they do, so we ignore the result and return.
         let _ = (|| {
@@ -125,67 +368,127 @@ impl Connection {
             self.error.flush()
         })();
     }
+
+    /// Logs the request that just finished, if we're keeping an access log and
+    /// got as far as responding.  Either way, the record is done with.
+    pub fn log_access(&mut self) {
+        let record = std::mem::take(&mut self.record);
+        if let (Some(format), Some(_)) = (self.log_format, &record.status) {
+            let entry = record.format(format, &self.remote);
+            // As with other log entries, failure isn't worth reporting.
+            let _ = self.write_log(&entry);
+        }
+    }
 }
 
+/// Checks whether a `send_file` failure means we should copy instead.
+fn cannot_send_file(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::Unsupported
+        || e.raw_os_error() == Some(libc::EINVAL)
+        || e.raw_os_error() == Some(libc::ENOSYS)
+}
+
+/// An in-memory output that can still be inspected after the connection
+/// writing to it is gone.
 #[cfg(test)]
-mod tests {
-    use crate::timeout;
-    use crate::unix;
-    use super::*;
-    use std::fs;
-    use std::io;
-    use std::mem;
-
-    use std::io::Write;
-
-    // Note: this test relies on buffering in the pipes.  Hoping for the best.
-    fn make_piped_connection() -> (Connection, fs::File, fs::File, fs::File) {
-        let pipe_to_con = unix::pipe().unwrap();
-        let pipe_from_con = unix::pipe().unwrap();
-        let error_from_con = unix::pipe().unwrap();
-
-        let c = Connection {
-            input: io::BufReader::new(timeout::SafeFile::new(
-                pipe_to_con.input,
-            )),
-            output: io::BufWriter::new(timeout::SafeFile::new(
-                pipe_from_con.output,
-            )),
-            error: io::BufWriter::new(error_from_con.output),
-            remote: "REMOTE".to_string(),
-        };
+#[derive(Clone, Default)]
+pub struct SharedBuffer(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
 
-        (
-            c,
-            pipe_to_con.output,
-            pipe_from_con.input,
-            error_from_con.input,
-        )
+#[cfg(test)]
+impl SharedBuffer {
+    pub fn contents(&self) -> Vec<u8> {
+        self.0.borrow().clone()
     }
+}
+
+#[cfg(test)]
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl Output for SharedBuffer {}
+
+#[cfg(test)]
+impl Connection {
+    /// Creates a connection that reads `input`, returning it along with the
+    /// buffers that receive its output and its log.
+    pub fn in_memory(input: &[u8]) -> (Connection, SharedBuffer, SharedBuffer) {
+        let output = SharedBuffer::default();
+        let error = SharedBuffer::default();
+        let c = Connection::new(
+            Box::new(io::Cursor::new(input.to_vec())),
+            Box::new(output.clone()),
+            Box::new(error.clone()),
+            "REMOTE".to_string(),
+            timeout::Timeouts::default(),
+        );
+        (c, output, error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
     #[test]
     fn test_connection_readline() {
-        let (mut c, mut to_con, _, _) = make_piped_connection();
+        let (mut c, _, _) = Connection::in_memory(
+            b"\r\nabcd\r\nohai\r\nalso just\nnewline\ntruncated",
+        );
 
-        to_con.write_all(b"\r\n").unwrap();
         assert_eq!(b"", &c.readline().unwrap()[..]);
-        to_con.write_all(b"abcd\r\nohai\r\n").unwrap();
         assert_eq!(b"abcd", &c.readline().unwrap()[..]);
         assert_eq!(b"ohai", &c.readline().unwrap()[..]);
 
         // Mostly for testing, but also as suggested by the spec, we also tolerate
         // pure Unix-style LF endings.
-        to_con.write_all(b"also just\nnewline\n").unwrap();
         assert_eq!(b"also just", &c.readline().unwrap()[..]);
         assert_eq!(b"newline", &c.readline().unwrap()[..]);
 
         // Test what happens when the connection is dropped.
-        to_con.write_all(b"truncated").unwrap();
-        mem::drop(to_con); // close our side of this pipe
         match c.readline().err() {
             Some(HttpError::ConnectionClosed) => (),
             Some(_) => panic!("Unexpected error from readline() at stream end"),
             _ => panic!("readline() must fail at stream end"),
         };
     }
+
+    #[test]
+    fn test_connection_write_file() {
+        let (mut c, output, _) = Connection::in_memory(b"");
+        let mut file = fs::File::open("Cargo.toml").unwrap();
+        let expected = fs::read("Cargo.toml").unwrap();
+
+        c.write(b">").unwrap();
+        assert_eq!(c.write_file(&mut file, 2, 5).unwrap(), 5);
+        assert_eq!(
+            c.write_file(&mut file, 0, expected.len() as u64 + 10)
+                .unwrap(),
+            expected.len() as u64
+        );
+        c.flush_output().unwrap();
+
+        let mut want = b">".to_vec();
+        want.extend_from_slice(&expected[2..7]);
+        want.extend_from_slice(&expected);
+        assert_eq!(output.contents(), want);
+    }
+
+    #[test]
+    fn test_connection_log() {
+        let (mut c, _, error) = Connection::in_memory(b"");
+        c.log(b"./x/y", Some(b"opening"), b"oops");
+        c.log_other(b"hello");
+        assert_eq!(
+            &error.contents()[..],
+            &b"REMOTE read ./x/y [opening]: oops\nREMOTE hello\n"[..]
+        );
+    }
 }
@@ -0,0 +1,53 @@
+//! Settings that shape how requests are served, read from the environment
+//! once at startup.
+
+use std::net::IpAddr;
+
+use nix::unistd::{Gid, Uid};
+
+use crate::access;
+use crate::timeout::Timeouts;
+
+/// How to serve requests.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// The identity to assume once we've `chroot`ed into the document root.
+    pub uid: Option<Uid>,
+    pub gid: Option<Gid>,
+    /// How long to wait for the client.
+    pub timeouts: Timeouts,
+    /// The format of the access log, if we're keeping one.
+    pub log_format: Option<access::Format>,
+    /// Names to try, in order, when a client asks for a directory.
+    pub index_names: Vec<Vec<u8>>,
+    /// Whether to list directories that have none of `index_names`.
+    pub autoindex: bool,
+    /// Whether to gzip text-like files ourselves, when there's no alternate.
+    pub compress: bool,
+    /// Where to keep the copies we compress, if anywhere.
+    pub compress_cache: Option<Vec<u8>>,
+    /// The normalized host to serve for hosts that have no directory.
+    pub default_host: Option<Vec<u8>>,
+    /// Whether clients reach us over HTTPS, by way of a proxy.
+    pub https: bool,
+    /// Clients whose `X-Forwarded-Proto` we believe.
+    pub trusted_proxies: Vec<IpAddr>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            uid: None,
+            gid: None,
+            timeouts: Timeouts::default(),
+            log_format: None,
+            index_names: vec![b"index.html".to_vec()],
+            autoindex: false,
+            compress: false,
+            compress_cache: None,
+            default_host: None,
+            https: false,
+            trusted_proxies: Vec::new(),
+        }
+    }
+}
@@ -1,10 +1,9 @@
 //! HTTP protocol errors
 
+use std::borrow::Cow;
 use std::io;
 use std::result;
 
-use std::error::Error;
-
 /// Errors that may kill off an HTTP request or connection.
 #[derive(Debug)]
 pub enum HttpError {
@@ -29,8 +28,8 @@ pub enum HttpError {
     /// 408 - The client didn't send data within the time we were willing to wait.
     RequestTimeout,
 
-    /// 412 - The client sent 'If-Match' or 'If-Unmodified-Since' headers, and we
-    /// are treating the test they described as having failed.
+    /// 412 - The client sent 'If-Match' or 'If-Unmodified-Since' headers, and
+    /// the resource doesn't satisfy them.
     PreconditionFailed,
 
     /// 417 - The client sent the 'Expect' header, which we were ironically not
@@ -49,6 +48,11 @@ pub enum HttpError {
     /// 505 - The protocol sent by the client was unrecognized.
     BadProtocol,
 
+    /// The client opened with the HTTP/2 connection preface instead of a
+    /// request.  Not an error as such: it tells the caller to switch
+    /// protocols.
+    Http2Preface,
+
     /// For convenience, `io::Error`s can be propagated as `HttpError`s.
     /// We treat them as internal server errors (500); any *expected* I/O errors
     /// should be coerced into another error type.
@@ -58,7 +62,7 @@ pub enum HttpError {
 impl HttpError {
     /// Returns the numeric HTTP status code appropriate for this error, along
     /// with a short ASCII-encoded explanatory message.
-    pub fn status<'a>(&'a self) -> Option<(&'a [u8], &'a [u8])> {
+    pub fn status(&self) -> Option<(&[u8], &[u8])> {
         use HttpError::*;
 
         match self {
@@ -70,7 +74,7 @@ impl HttpError {
             IoError(_) => Some((b"500", b"I/O error")),
 
             // Everything else is straightforward.
-            ConnectionClosed => None,
+            ConnectionClosed | Http2Preface => None,
             BadRequest => Some((b"400", b"bad request")),
             RequestTimeout => Some((b"408", b"type faster")),
             PreconditionFailed => Some((b"412", b"precondition failed")),
@@ -83,21 +87,22 @@ impl HttpError {
 
     /// Returns a description of this error appropriate for a trusted audience,
     /// such as a log file.
-    pub fn log_message<'a>(&'a self) -> Option<&'a [u8]> {
+    pub fn log_message(&self) -> Option<Cow<'_, [u8]>> {
         use self::HttpError::*;
 
-        match self {
-            ConnectionClosed => None,
-            BadRequest => Some(b"bad request"),
-            NotFound(m) => Some(m),
-            RequestTimeout => None,
-            PreconditionFailed => Some(b"precondition failed"),
-            SpanishInquisition => Some(b"unexpected"),
-            IoError(e) => Some(e.description().as_bytes()),
-            BadMethod => Some(b"bad method"),
-            NotImplemented(m) => Some(m),
-            BadProtocol => Some(b"bad protocol"),
-        }
+        let message: &[u8] = match self {
+            ConnectionClosed | Http2Preface => return None,
+            BadRequest => b"bad request",
+            NotFound(m) => m,
+            RequestTimeout => return None,
+            PreconditionFailed => b"precondition failed",
+            SpanishInquisition => b"unexpected",
+            IoError(e) => return Some(e.to_string().into_bytes().into()),
+            BadMethod => b"bad method",
+            NotImplemented(m) => m,
+            BadProtocol => b"bad protocol",
+        };
+        Some(message.into())
     }
 }
 
@@ -0,0 +1,240 @@
+//! Entity tags and the conditional headers that carry them (RFC 7232).
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::file::OpenFile;
+use crate::request::trim_ws;
+use crate::response::ContentEncoding;
+
+/// An entity tag.  `opaque` holds the quoted string exactly as it appears on
+/// the wire, quotes included.
+#[derive(Debug, PartialEq, Clone)]
+pub struct EntityTag {
+    pub weak: bool,
+    pub opaque: Vec<u8>,
+}
+
+impl EntityTag {
+    /// Generates a tag for `resource` as served with `encoding`.
+    ///
+    /// The tag is derived from the file's identity, size and modification time,
+    /// so it changes whenever the contents plausibly could.  The one case where
+    /// that reasoning fails is a recently modified file -- so, following
+    /// Apache, those get weak tags.
+    pub fn for_file(
+        resource: &OpenFile,
+        encoding: Option<ContentEncoding>,
+        now: SystemTime,
+    ) -> EntityTag {
+        let mtime = resource
+            .mtime
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let weak = resource.recently_modified(now);
+
+        let mut opaque = format!(
+            "\"{:x}-{:x}-{:x}",
+            resource.inode,
+            resource.length,
+            mtime.as_nanos()
+        )
+        .into_bytes();
+        if let Some(encoding) = encoding {
+            opaque.push(b'-');
+            opaque.extend_from_slice(encoding.extension());
+        }
+        opaque.push(b'"');
+
+        EntityTag { weak, opaque }
+    }
+
+    /// Parses a single entity tag, which must make up the entirety of `value`.
+    pub fn parse(value: &[u8]) -> Option<EntityTag> {
+        let (weak, opaque) = if value.starts_with(b"W/") {
+            (true, &value[2..])
+        } else {
+            (false, value)
+        };
+
+        // Between the quotes, any visible character but '"' is fair game.
+        if opaque.len() < 2
+            || opaque[0] != b'"'
+            || opaque[opaque.len() - 1] != b'"'
+            || opaque[1..opaque.len() - 1]
+                .iter()
+                .any(|&b| b == b'"' || b < 0x21 || b == 0x7F)
+        {
+            return None;
+        }
+
+        Some(EntityTag {
+            weak,
+            opaque: opaque.to_vec(),
+        })
+    }
+
+    /// Strong comparison: both tags must be strong and identical.
+    pub fn strong_eq(&self, other: &EntityTag) -> bool {
+        !self.weak && !other.weak && self.opaque == other.opaque
+    }
+
+    /// Weak comparison: the opaque parts must be identical.
+    pub fn weak_eq(&self, other: &EntityTag) -> bool {
+        self.opaque == other.opaque
+    }
+
+    /// Renders the tag as it should appear in an `ETag` header.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.opaque.len() + 2);
+        if self.weak {
+            out.extend_from_slice(b"W/");
+        }
+        out.extend_from_slice(&self.opaque);
+        out
+    }
+}
+
+/// The value of an `If-Match` or `If-None-Match` header.
+#[derive(Debug, PartialEq)]
+pub enum Condition {
+    /// `*`, which matches any current representation.
+    Any,
+    /// A list of entity tags.
+    Tags(Vec<EntityTag>),
+}
+
+impl Condition {
+    /// Parses a header value.  Returns `None` if the value is malformed, in
+    /// which case the header should be ignored.
+    pub fn parse(value: &[u8]) -> Option<Condition> {
+        let value = trim_ws(value);
+        if value == b"*" {
+            return Some(Condition::Any);
+        }
+
+        // Entity tags can contain commas, so we can't just split on them.
+        let mut tags = Vec::new();
+        let mut rest = value;
+        loop {
+            rest = trim_ws(rest);
+            if rest.is_empty() {
+                break;
+            }
+            if rest[0] == b',' {
+                // Empty list elements are permitted.
+                rest = &rest[1..];
+                continue;
+            }
+
+            let start = if rest.starts_with(b"W/") { 2 } else { 0 };
+            let close = rest
+                .iter()
+                .skip(start + 1)
+                .position(|&b| b == b'"')
+                .map(|i| start + 1 + i)?;
+            tags.push(EntityTag::parse(&rest[..=close])?);
+            rest = &rest[close + 1..];
+
+            rest = trim_ws(rest);
+            if !rest.is_empty() && rest[0] != b',' {
+                return None;
+            }
+        }
+
+        if tags.is_empty() {
+            None
+        } else {
+            Some(Condition::Tags(tags))
+        }
+    }
+
+    /// Folds another copy of the same header into this one.  These headers are
+    /// lists, so repeating them is equivalent to concatenating them.
+    pub fn merge(&mut self, other: Condition) {
+        match (self, other) {
+            (Condition::Tags(mine), Condition::Tags(theirs)) => {
+                mine.extend(theirs)
+            }
+            (this, _) => *this = Condition::Any,
+        }
+    }
+
+    /// Checks whether `tag` satisfies the condition under strong comparison,
+    /// as used by `If-Match`.
+    pub fn matches_strong(&self, tag: &EntityTag) -> bool {
+        match self {
+            Condition::Any => true,
+            Condition::Tags(tags) => tags.iter().any(|t| t.strong_eq(tag)),
+        }
+    }
+
+    /// Checks whether `tag` satisfies the condition under weak comparison, as
+    /// used by `If-None-Match`.
+    pub fn matches_weak(&self, tag: &EntityTag) -> bool {
+        match self {
+            Condition::Any => true,
+            Condition::Tags(tags) => tags.iter().any(|t| t.weak_eq(tag)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tag(weak: bool, opaque: &[u8]) -> EntityTag {
+        EntityTag {
+            weak,
+            opaque: opaque.to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_parse_tag() {
+        assert_eq!(EntityTag::parse(b"\"abc\""), Some(tag(false, b"\"abc\"")));
+        assert_eq!(EntityTag::parse(b"W/\"abc\""), Some(tag(true, b"\"abc\"")));
+        assert_eq!(EntityTag::parse(b"\"\""), Some(tag(false, b"\"\"")));
+        assert_eq!(EntityTag::parse(b"abc"), None);
+        assert_eq!(EntityTag::parse(b"\"a\"b\""), None);
+        assert_eq!(EntityTag::parse(b"w/\"abc\""), None);
+        assert_eq!(EntityTag::parse(b"\""), None);
+    }
+
+    #[test]
+    fn test_parse_condition() {
+        assert_eq!(Condition::parse(b" * "), Some(Condition::Any));
+        assert_eq!(
+            Condition::parse(b"\"a,b\", W/\"c\",,"),
+            Some(Condition::Tags(vec![
+                tag(false, b"\"a,b\""),
+                tag(true, b"\"c\""),
+            ])),
+        );
+        assert_eq!(Condition::parse(b""), None);
+        assert_eq!(Condition::parse(b"\"a\" \"b\""), None);
+        assert_eq!(Condition::parse(b"\"a\", *"), None);
+        assert_eq!(Condition::parse(b"\"unterminated"), None);
+    }
+
+    #[test]
+    fn test_comparison() {
+        let strong = tag(false, b"\"1\"");
+        let weak = tag(true, b"\"1\"");
+        assert!(strong.strong_eq(&strong));
+        assert!(!strong.strong_eq(&weak));
+        assert!(!weak.strong_eq(&weak));
+        assert!(strong.weak_eq(&weak));
+        assert!(!strong.weak_eq(&tag(false, b"\"2\"")));
+
+        let mut c = Condition::parse(b"\"2\"").unwrap();
+        assert!(!c.matches_weak(&weak));
+        c.merge(Condition::parse(b"W/\"1\"").unwrap());
+        assert!(c.matches_weak(&strong));
+        assert!(!c.matches_strong(&strong));
+        c.merge(Condition::parse(b"\"1\"").unwrap());
+        assert!(c.matches_strong(&strong));
+        assert!(!c.matches_strong(&weak));
+        c.merge(Condition::Any);
+        assert_eq!(c, Condition::Any);
+    }
+}
@@ -1,9 +1,16 @@
 //! File access operations.
 
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi;
 use std::fs;
+use std::io::Read;
+use std::os::unix::ffi::OsStrExt;
 use std::os::unix::fs::MetadataExt;
 use std::path;
-use std::time::SystemTime;
+use std::rc::Rc;
+use std::thread::LocalKey;
+use std::time::{Duration, SystemTime};
 
 use crate::error;
 
@@ -21,23 +28,87 @@ where
     let f = fs::File::open(path)?;
     let meta = f.metadata()?;
 
-    if (meta.mode() & 0o444) != 0o444 {
-        Err(error::HttpError::NotFound(b"not ugo+r"))
-    } else if (meta.mode() & 0o101) == 0o001 {
-        Err(error::HttpError::NotFound(b"o+x but u-x"))
-    } else if meta.is_dir() {
+    check_mode(&meta)?;
+    if meta.is_dir() {
         Ok(FileOrDir::Dir)
     } else if meta.is_file() {
         Ok(FileOrDir::File(OpenFile {
             file: f,
             mtime: meta.modified()?,
             length: meta.len(),
+            inode: meta.ino(),
         }))
     } else {
         Err(error::HttpError::NotFound(b"not a regular file"))
     }
 }
 
+/// Reads a small configuration file from the document root, such as a
+/// host's `.cache-control`.  It's subject to the same checks as the files we
+/// serve, so that the permissions mean the same thing everywhere.  Returns
+/// `None` if there's no such file we're willing to use.
+pub fn read_config(path: &[u8]) -> Option<Vec<u8>> {
+    // Configuration should be small.  Anything bigger is probably a mistake.
+    const MAX_CONFIG_BYTES: u64 = 1 << 16;
+
+    let config = match safe_open(ffi::OsStr::from_bytes(path)) {
+        Ok(FileOrDir::File(f)) => f,
+        _ => return None,
+    };
+    let mut contents = Vec::new();
+    config
+        .file
+        .take(MAX_CONFIG_BYTES + 1)
+        .read_to_end(&mut contents)
+        .ok()?;
+    if contents.len() as u64 > MAX_CONFIG_BYTES {
+        return None;
+    }
+    Some(contents)
+}
+
+/// Configuration files, parsed, by path.  A process only lives as long as its
+/// connection, so the files can't get very stale -- and HTTP/2 streams, which
+/// get connections of their own, share them too.
+pub type ConfigCache<T> = RefCell<HashMap<Vec<u8>, Rc<T>>>;
+
+/// Gets what's kept in `cache` for `key`, calling `load` to read and parse it
+/// the first time it's asked for.
+pub fn cached<T>(
+    cache: &'static LocalKey<ConfigCache<T>>,
+    key: &[u8],
+    load: impl FnOnce() -> T,
+) -> Rc<T> {
+    if let Some(found) = cache.with(|c| c.borrow().get(key).cloned()) {
+        return found;
+    }
+    let loaded = Rc::new(load());
+    cache.with(|c| c.borrow_mut().insert(key.to_vec(), loaded.clone()));
+    loaded
+}
+
+/// Splits a configuration file into its meaningful lines, trimmed of
+/// surrounding whitespace, including any carriage return.  Blank lines, and
+/// comments -- lines starting with `#` -- are left out.
+pub fn config_lines(config: &[u8]) -> impl Iterator<Item = &[u8]> {
+    config
+        .split(|&b| b == b'\n')
+        .map(<[u8]>::trim_ascii)
+        .filter(|line| !line.is_empty() && !line.starts_with(b"#"))
+}
+
+/// Applies `safe_open`'s permission checks to a file's metadata, for callers
+/// that need to know whether a file would be served without opening it.
+pub fn check_mode(meta: &fs::Metadata) -> error::Result<()> {
+    if (meta.mode() & 0o444) != 0o444 {
+        Err(error::HttpError::NotFound(b"not ugo+r"))
+    } else if (meta.mode() & 0o101) == 0o001 {
+        Err(error::HttpError::NotFound(b"o+x but u-x"))
+    } else {
+        Ok(())
+    }
+}
+
 /// Used to represent the result of opening a path, which might have turned out
 /// to be a directory.
 pub enum FileOrDir {
@@ -55,4 +126,53 @@ pub struct OpenFile {
     /// The file's length, at the last time we checked.  Note that this may change
     /// at runtime; take care.
     pub length: u64,
+    /// The file's inode number, used to tell apart files that happen to share
+    /// a length and mtime.
+    pub inode: u64,
+}
+
+impl OpenFile {
+    /// Checks whether the file was modified within a second of `now` (or in
+    /// the future).  Such a file may still be in the middle of being written,
+    /// and could change again without its mtime moving.
+    pub fn recently_modified(&self, now: SystemTime) -> bool {
+        self.mtime
+            .checked_add(Duration::from_secs(1))
+            .is_none_or(|t| t > now)
+    }
+}
+
+/// A scratch directory for a test, removed with everything in it when the
+/// test is done with it, whether or not the test passed.
+#[cfg(test)]
+pub struct TempDir(path::PathBuf);
+
+#[cfg(test)]
+impl TempDir {
+    /// Creates an empty directory, named for `tag` and this process.
+    pub fn new(tag: &str) -> TempDir {
+        let dir = std::env::temp_dir().join(format!(
+            "httpd-{}-{}",
+            tag,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        TempDir(dir)
+    }
+
+    pub fn path(&self) -> &path::Path {
+        &self.0
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        self.0.as_os_str().as_bytes()
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
 }
@@ -29,9 +29,30 @@ pub fn from_path(file_path: &[u8]) -> Cow<'static, [u8]> {
         .unwrap_or_else(|| Cow::from(b"text/plain" as &[u8]))
 }
 
+/// Guesses whether content of type `content_type` is worth compressing: text,
+/// and the structured formats that are text in disguise.
+pub fn is_compressible(content_type: &[u8]) -> bool {
+    let essence = content_type
+        .split(|&b| b == b';')
+        .next()
+        .unwrap_or_default()
+        .trim_ascii()
+        .to_ascii_lowercase();
+    essence.starts_with(b"text/")
+        || essence.ends_with(b"+xml")
+        || essence.ends_with(b"+json")
+        || matches!(
+            &essence[..],
+            b"application/json"
+                | b"application/javascript"
+                | b"application/xml"
+        )
+}
+
 fn canned_mapping(ext: &[u8]) -> Cow<'static, [u8]> {
     let mimetype: &[u8] = match ext {
-        b"html" => b"text/html",
+        b"html" | b"htm" => b"text/html",
+        b"xhtml" => b"application/xhtml+xml",
         b"gif" => b"image/gif",
         b"jpeg" | b"jpg" => b"image/jpeg",
         b"png" => b"image/png",
@@ -64,4 +85,15 @@ mod tests {
     from_path_case!(test_no_extension, b"foobar", b"text/plain");
     from_path_case!(test_canned, b"foobar.css", b"text/css");
     // Deliberately *not* exercising the complete canned mapping.
+
+    #[test]
+    fn test_is_compressible() {
+        use super::is_compressible;
+        assert!(is_compressible(b"text/plain"));
+        assert!(is_compressible(b"Text/HTML; charset=utf-8"));
+        assert!(is_compressible(b"image/svg+xml"));
+        assert!(is_compressible(b"application/json"));
+        assert!(!is_compressible(b"image/png"));
+        assert!(!is_compressible(b"application/octet-stream"));
+    }
 }
@@ -0,0 +1,180 @@
+//! Extra response headers, from `.headers` files in a virtual host's
+//! directory tree.
+//!
+//! Each line of a `.headers` file is a header to send with the files in that
+//! directory and those beneath it:
+//!
+//! ```text
+//! # Applies to the whole host, if this is at its top.
+//! X-Content-Type-Options: nosniff
+//! Content-Security-Policy: default-src 'self'
+//! Link: </style.css>; rel=preload; as=style
+//! ```
+//!
+//! Files are read from the top of the host down to the directory of the file
+//! being served.  Headers named in a deeper file replace those of the same
+//! name from above, and a line with no value just removes them.  A name may
+//! appear more than once in one file, to send it more than once.
+//!
+//! Lines that aren't well-formed headers are ignored.  That includes anything
+//! with a stray carriage return or other control character in it, which could
+//! otherwise be used to inject headers (or a body) of its own.  So are headers
+//! that this server takes care of itself, like `Content-Length`, whose values
+//! it has to get right.
+//!
+//! Each file is read the first time a connection needs it, and kept until the
+//! connection ends.
+
+use std::cell::RefCell;
+
+use crate::file;
+
+/// Headers we send ourselves, and won't take from a `.headers` file.  Cache
+/// headers have their own file, `.cache-control`.
+const RESERVED: &[&[u8]] = &[
+    b"accept-ranges",
+    b"cache-control",
+    b"connection",
+    b"content-encoding",
+    b"content-length",
+    b"content-range",
+    b"content-type",
+    b"date",
+    b"etag",
+    b"expires",
+    b"keep-alive",
+    b"last-modified",
+    b"proxy-connection",
+    b"server",
+    b"transfer-encoding",
+    b"upgrade",
+    b"vary",
+];
+
+/// A header from a `.headers` file.  An empty value means "don't send this".
+#[derive(Debug, PartialEq, Clone)]
+pub struct Header {
+    pub name: Vec<u8>,
+    pub value: Vec<u8>,
+}
+
+thread_local! {
+    /// Each `.headers` file's headers, by path.
+    static FILES: file::ConfigCache<Vec<Header>> = RefCell::default();
+}
+
+/// Collects the extra headers for the file at `path`, from the `.headers`
+/// files between `root` and the file's own directory.
+pub fn lookup(root: &[u8], path: &[u8]) -> Vec<Header> {
+    let mut headers: Vec<Header> = Vec::new();
+    let mut dir = root.to_vec();
+    // Every directory from the root to the file's own, which is everything up
+    // to each slash.
+    for (i, _) in path.iter().enumerate().filter(|&(_, &b)| b == b'/') {
+        dir.truncate(root.len());
+        dir.extend_from_slice(&path[..=i]);
+        dir.extend_from_slice(b".headers");
+        let rules = file::cached(&FILES, &dir, || {
+            file::read_config(&dir)
+                .map(|contents| parse(&contents))
+                .unwrap_or_default()
+        });
+        headers.retain(|h| {
+            !rules.iter().any(|r| r.name.eq_ignore_ascii_case(&h.name))
+        });
+        headers.extend(rules.iter().cloned());
+    }
+    headers.retain(|h| !h.value.is_empty());
+    headers
+}
+
+fn parse(rules: &[u8]) -> Vec<Header> {
+    file::config_lines(rules)
+        .filter_map(|line| {
+            let colon = line.iter().position(|&b| b == b':')?;
+            let (name, value) =
+                (&line[..colon], line[colon + 1..].trim_ascii());
+
+            let name_ok = !name.is_empty() && name.iter().all(|&b| is_tchar(b));
+            let value_ok = value.iter().all(|&b| b == b'\t' || b >= b' ')
+                && !value.contains(&0x7F);
+            let reserved =
+                RESERVED.iter().any(|r| r.eq_ignore_ascii_case(name));
+            if !name_ok || !value_ok || reserved {
+                return None;
+            }
+            Some(Header {
+                name: name.to_vec(),
+                value: value.to_vec(),
+            })
+        })
+        .collect()
+}
+
+/// Checks whether `b` can appear in a header name (RFC 9110 section 5.6.2).
+fn is_tchar(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file::TempDir;
+    use std::fs;
+
+    fn header(name: &str, value: &str) -> Header {
+        Header {
+            name: name.into(),
+            value: value.into(),
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let rules = b"# comment\r\n\
+                      \t# indented: comment\n\
+                      X-Content-Type-Options:  nosniff\r\n\
+                      \n\
+                      Link: </a.css>; rel=preload\n\
+                      Link: </b.js>; rel=preload\n\
+                      X-Injected: a\rSet-Cookie: b\n\
+                      X-Nul: a\x00b\n\
+                      Bad Name: value\n\
+                      Content-Length: 0\n\
+                      no colon\n\
+                      X-Removed:\n";
+        assert_eq!(
+            parse(rules),
+            vec![
+                header("X-Content-Type-Options", "nosniff"),
+                header("Link", "</a.css>; rel=preload"),
+                header("Link", "</b.js>; rel=preload"),
+                header("X-Removed", ""),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lookup() {
+        let dir = TempDir::new("headers");
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        fs::write(dir.path().join(".headers"), b"A: 1\nB: 2\nC: 3\n").unwrap();
+        fs::write(dir.path().join("sub/.headers"), b"b: two\nC:\nD: 4\n")
+            .unwrap();
+
+        let top = lookup(dir.as_bytes(), b"/index.html");
+        let sub = lookup(dir.as_bytes(), b"/sub/index.html");
+        assert_eq!(
+            top,
+            vec![header("A", "1"), header("B", "2"), header("C", "3")]
+        );
+        assert_eq!(
+            sub,
+            vec![header("A", "1"), header("b", "two"), header("D", "4")]
+        );
+
+        // Once read, a file's headers stick for the life of the process.
+        fs::write(dir.path().join("sub/.headers"), b"D: 5\n").unwrap();
+        assert_eq!(lookup(dir.as_bytes(), b"/sub/index.html"), sub);
+    }
+}
@@ -0,0 +1,984 @@
+//! HTTP/2 (RFC 9113), for clients that open with its connection preface --
+//! either because they know we speak it ("prior knowledge"), or because we
+//! agreed on it during the TLS handshake.
+//!
+//! Rather than teach the rest of the server a second protocol, we translate.
+//! Each stream's request is rewritten as the HTTP/1.1 request it's equivalent
+//! to, and served by `server::serve_http2_stream` over a `Connection` of its
+//! own; the response that comes back is turned into HEADERS and DATA frames.
+//! Streams are served one at a time, in the order they were opened, which
+//! still saves clients a connection (and a handshake) per request.
+
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use loona_hpack::Decoder;
+
+use crate::con::{Connection, Output};
+use crate::error::*;
+use crate::request::trim_ws;
+use crate::server;
+use crate::timeout::Timeouts;
+
+// Frame types.
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PUSH_PROMISE: u8 = 0x5;
+const PING: u8 = 0x6;
+const GOAWAY: u8 = 0x7;
+const WINDOW_UPDATE: u8 = 0x8;
+const CONTINUATION: u8 = 0x9;
+
+// Frame flags.
+const END_STREAM: u8 = 0x1;
+const ACK: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+const PADDED: u8 = 0x8;
+const PRIORITY: u8 = 0x20;
+
+// Settings.
+const SETTINGS_ENABLE_PUSH: u16 = 0x2;
+const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+
+// Error codes.
+const NO_ERROR: u32 = 0x0;
+const PROTOCOL_ERROR: u32 = 0x1;
+const INTERNAL_ERROR: u32 = 0x2;
+const FLOW_CONTROL_ERROR: u32 = 0x3;
+const FRAME_SIZE_ERROR: u32 = 0x6;
+const REFUSED_STREAM: u32 = 0x7;
+const COMPRESSION_ERROR: u32 = 0x9;
+const ENHANCE_YOUR_CALM: u32 = 0xB;
+
+/// The largest frame payload either side may send until told otherwise, and
+/// the largest we'll accept.
+const DEFAULT_MAX_FRAME_SIZE: usize = 1 << 14;
+/// The flow control window each side starts with.
+const DEFAULT_WINDOW: i64 = 65_535;
+const MAX_WINDOW: i64 = (1 << 31) - 1;
+/// The most requests we'll hold while serving another.
+const MAX_PENDING: usize = 100;
+/// The most header data we'll accept for one request, compressed or not.
+const MAX_HEADER_BYTES: usize = 1 << 16;
+
+/// Hop-by-hop headers, which mean nothing in HTTP/2.
+const CONNECTION_SPECIFIC: &[&[u8]] = &[
+    b"connection",
+    b"keep-alive",
+    b"proxy-connection",
+    b"transfer-encoding",
+    b"upgrade",
+];
+
+/// Serves an HTTP/2 connection on `con`, whose client has just sent the
+/// connection preface.
+pub fn serve(con: Connection) -> Result<()> {
+    let remote = con.remote().to_string();
+    let log_format = con.log_format();
+    let secure = con.is_secure();
+    let config = con.config();
+    let session = Rc::new(RefCell::new(Session::new(con)));
+
+    let result = (|| {
+        session.borrow_mut().start()?;
+        loop {
+            let next = session.borrow_mut().next_request()?;
+            let (id, request) = match next {
+                Some(r) => r,
+                None => return session.borrow_mut().go_away(NO_ERROR),
+            };
+
+            let mut c = Connection::new(
+                Box::new(io::Cursor::new(request)),
+                Box::new(StreamOutput(session.clone())),
+                Box::new(SessionLog(session.clone())),
+                remote.clone(),
+                Timeouts::default(),
+            );
+            c.set_log_format(log_format);
+            c.set_secure(secure);
+            c.set_config(config.clone());
+            session.borrow_mut().current = Some(Current {
+                id,
+                head: Some(Vec::new()),
+                body: Vec::new(),
+            });
+            let served = server::serve_http2_stream(c).is_ok();
+            session.borrow_mut().end_stream(served)?;
+        }
+    })();
+
+    match result {
+        Err(HttpError::ConnectionClosed) | Err(HttpError::RequestTimeout) => {
+            Ok(())
+        }
+        r => r,
+    }
+}
+
+struct Frame {
+    kind: u8,
+    flags: u8,
+    stream: u32,
+    payload: Vec<u8>,
+}
+
+/// The stream being served.
+struct Current {
+    id: u32,
+    /// The response head, until it's complete and we've sent it as HEADERS.
+    head: Option<Vec<u8>>,
+    /// Body data waiting to fill a frame.
+    body: Vec<u8>,
+}
+
+struct Session {
+    con: Connection,
+    /// Set once the connection is unusable; everything fails from then on.
+    dead: bool,
+    /// Whether the client has sent its SETTINGS, which must come first.
+    settled: bool,
+    /// Whether the client has told us it's leaving.
+    going_away: bool,
+    decoder: Decoder<'static>,
+    /// A header block still arriving in CONTINUATION frames, and its stream.
+    partial: Option<(u32, Vec<u8>)>,
+    /// The highest stream the client has opened.
+    last_stream: u32,
+    /// Requests waiting to be served, translated into HTTP/1.1.
+    pending: VecDeque<(u32, Vec<u8>)>,
+    /// Send windows of the streams we owe responses, by id.  A stream that
+    /// isn't here has been reset, or finished.
+    windows: HashMap<u32, i64>,
+    /// The connection's send window.
+    window: i64,
+    /// The client's SETTINGS_INITIAL_WINDOW_SIZE.
+    initial_window: i64,
+    /// The client's SETTINGS_MAX_FRAME_SIZE.
+    max_frame_size: usize,
+    current: Option<Current>,
+}
+
+impl Session {
+    fn new(con: Connection) -> Session {
+        Session {
+            con,
+            dead: false,
+            settled: false,
+            going_away: false,
+            decoder: Decoder::new(),
+            partial: None,
+            last_stream: 0,
+            pending: VecDeque::new(),
+            windows: HashMap::new(),
+            window: DEFAULT_WINDOW,
+            initial_window: DEFAULT_WINDOW,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            current: None,
+        }
+    }
+
+    /// Sends our half of the connection preface.
+    fn start(&mut self) -> Result<()> {
+        let mut settings = Vec::new();
+        settings
+            .extend_from_slice(&SETTINGS_MAX_CONCURRENT_STREAMS.to_be_bytes());
+        settings.extend_from_slice(&(MAX_PENDING as u32).to_be_bytes());
+        self.write_frame(SETTINGS, 0, 0, &settings)
+    }
+
+    /// Reads frames until there's a request to serve.  Returns `None` once
+    /// the client is done.
+    fn next_request(&mut self) -> Result<Option<(u32, Vec<u8>)>> {
+        loop {
+            if let Some(next) = self.pending.pop_front() {
+                return Ok(Some(next));
+            }
+            if self.going_away {
+                return Ok(None);
+            }
+            let frame = self.read_frame()?;
+            self.handle(frame)?;
+        }
+    }
+
+    fn read_frame(&mut self) -> Result<Frame> {
+        let result = self.read_frame_unchecked();
+        if result.is_err() {
+            self.dead = true;
+        }
+        result
+    }
+
+    fn read_frame_unchecked(&mut self) -> Result<Frame> {
+        if self.dead {
+            return Err(HttpError::ConnectionClosed);
+        }
+        // The client may be waiting on whatever we've buffered.
+        self.con.flush_output()?;
+
+        // Waiting for a frame is like waiting for a request, with the same
+        // timeouts.
+        self.con.begin_request()?;
+        let mut header = [0; 9];
+        self.con.read_exact(&mut header)?;
+        let length = (header[0] as usize) << 16
+            | (header[1] as usize) << 8
+            | header[2] as usize;
+        if length > DEFAULT_MAX_FRAME_SIZE {
+            return self.go_away(FRAME_SIZE_ERROR);
+        }
+        let mut payload = vec![0; length];
+        self.con.read_exact(&mut payload)?;
+        self.con.end_request();
+
+        Ok(Frame {
+            kind: header[3],
+            flags: header[4],
+            stream: u32::from_be_bytes([
+                header[5], header[6], header[7], header[8],
+            ]) & 0x7FFF_FFFF,
+            payload,
+        })
+    }
+
+    fn handle(&mut self, frame: Frame) -> Result<()> {
+        // A header block can't be interrupted, and the client's SETTINGS must
+        // come before anything else.
+        if self.partial.as_ref().is_some_and(|&(id, _)| {
+            frame.kind != CONTINUATION || frame.stream != id
+        }) || (!self.settled && frame.kind != SETTINGS)
+        {
+            return self.go_away(PROTOCOL_ERROR);
+        }
+
+        let id = frame.stream;
+        match frame.kind {
+            // We don't accept request bodies.  Requests that announce one are
+            // refused by `request::read`, so there's nothing to do with them.
+            DATA if id == 0 => self.go_away(PROTOCOL_ERROR),
+            DATA => Ok(()),
+
+            HEADERS => {
+                if id.is_multiple_of(2) || id <= self.last_stream {
+                    return self.go_away(PROTOCOL_ERROR);
+                }
+                self.last_stream = id;
+                let block = match header_block(&frame) {
+                    Some(b) => b.to_vec(),
+                    None => return self.go_away(PROTOCOL_ERROR),
+                };
+                self.header_block(id, block, frame.flags)
+            }
+            CONTINUATION => match self.partial.take() {
+                Some((_, mut block)) => {
+                    block.extend_from_slice(&frame.payload);
+                    self.header_block(id, block, frame.flags)
+                }
+                None => self.go_away(PROTOCOL_ERROR),
+            },
+
+            RST_STREAM if id == 0 => self.go_away(PROTOCOL_ERROR),
+            RST_STREAM if frame.payload.len() != 4 => {
+                self.go_away(FRAME_SIZE_ERROR)
+            }
+            RST_STREAM => {
+                self.windows.remove(&id);
+                self.pending.retain(|&(p, _)| p != id);
+                Ok(())
+            }
+
+            SETTINGS if id != 0 => self.go_away(PROTOCOL_ERROR),
+            SETTINGS if frame.flags & ACK != 0 => {
+                if frame.payload.is_empty() {
+                    Ok(())
+                } else {
+                    self.go_away(FRAME_SIZE_ERROR)
+                }
+            }
+            SETTINGS => self.settings(&frame.payload),
+
+            // Only servers push.
+            PUSH_PROMISE => self.go_away(PROTOCOL_ERROR),
+
+            PING if id != 0 => self.go_away(PROTOCOL_ERROR),
+            PING if frame.payload.len() != 8 => self.go_away(FRAME_SIZE_ERROR),
+            PING if frame.flags & ACK == 0 => {
+                self.write_frame(PING, ACK, 0, &frame.payload)
+            }
+
+            // Finish what we've started, then stop.
+            GOAWAY => {
+                self.going_away = true;
+                Ok(())
+            }
+
+            WINDOW_UPDATE if frame.payload.len() != 4 => {
+                self.go_away(FRAME_SIZE_ERROR)
+            }
+            WINDOW_UPDATE => {
+                let p = &frame.payload;
+                let increment = (u32::from_be_bytes([p[0], p[1], p[2], p[3]])
+                    & 0x7FFF_FFFF) as i64;
+                if id == 0 {
+                    self.window += increment;
+                    return if increment == 0 {
+                        self.go_away(PROTOCOL_ERROR)
+                    } else if self.window > MAX_WINDOW {
+                        self.go_away(FLOW_CONTROL_ERROR)
+                    } else {
+                        Ok(())
+                    };
+                }
+
+                // Closed streams can receive these for a while.
+                let window = match self.windows.get_mut(&id) {
+                    Some(w) => w,
+                    None => return Ok(()),
+                };
+                *window += increment;
+                if increment == 0 {
+                    self.reset(id, PROTOCOL_ERROR)
+                } else if *window > MAX_WINDOW {
+                    self.reset(id, FLOW_CONTROL_ERROR)
+                } else {
+                    Ok(())
+                }
+            }
+
+            // PRIORITY is advice we've no use for, and unknown frame types
+            // must be ignored.
+            _ => Ok(()),
+        }
+    }
+
+    /// Applies the client's settings and acknowledges them.
+    fn settings(&mut self, payload: &[u8]) -> Result<()> {
+        if !payload.len().is_multiple_of(6) {
+            return self.go_away(FRAME_SIZE_ERROR);
+        }
+
+        for setting in payload.chunks(6) {
+            let value = u32::from_be_bytes([
+                setting[2], setting[3], setting[4], setting[5],
+            ]);
+            match u16::from_be_bytes([setting[0], setting[1]]) {
+                SETTINGS_ENABLE_PUSH if value > 1 => {
+                    return self.go_away(PROTOCOL_ERROR)
+                }
+                SETTINGS_INITIAL_WINDOW_SIZE => {
+                    let value = value as i64;
+                    if value > MAX_WINDOW {
+                        return self.go_away(FLOW_CONTROL_ERROR);
+                    }
+                    // This applies retroactively to open streams.
+                    let delta = value - self.initial_window;
+                    self.initial_window = value;
+                    for w in self.windows.values_mut() {
+                        *w += delta;
+                    }
+                    if self.windows.values().any(|&w| w > MAX_WINDOW) {
+                        return self.go_away(FLOW_CONTROL_ERROR);
+                    }
+                }
+                SETTINGS_MAX_FRAME_SIZE => {
+                    if !(1 << 14..1 << 24).contains(&value) {
+                        return self.go_away(PROTOCOL_ERROR);
+                    }
+                    self.max_frame_size = value as usize;
+                }
+                // Our header compression never uses the dynamic table, so its
+                // size doesn't matter; nor do limits on what we never send.
+                _ => (),
+            }
+        }
+
+        self.settled = true;
+        self.write_frame(SETTINGS, ACK, 0, &[])
+    }
+
+    /// Accepts a piece of a request's header block, and queues the request
+    /// once the block is complete.
+    fn header_block(
+        &mut self,
+        id: u32,
+        block: Vec<u8>,
+        flags: u8,
+    ) -> Result<()> {
+        if block.len() > MAX_HEADER_BYTES {
+            return self.go_away(ENHANCE_YOUR_CALM);
+        }
+        if flags & END_HEADERS == 0 {
+            self.partial = Some((id, block));
+            return Ok(());
+        }
+
+        // The block must be decoded even if we won't serve the request, to
+        // keep our view of the compression state in step with the client's.
+        // Past our limit, though, we stop keeping the results.
+        let mut fields = Vec::new();
+        let mut size = 0;
+        let decoded = self.decoder.decode_with_cb(&block, |name, value| {
+            // Sizes as defined for SETTINGS_MAX_HEADER_LIST_SIZE.
+            size += name.len() + value.len() + 32;
+            if size <= MAX_HEADER_BYTES {
+                fields.push((name.into_owned(), value.into_owned()));
+            }
+        });
+        if decoded.is_err() {
+            return self.go_away(COMPRESSION_ERROR);
+        }
+        if size > MAX_HEADER_BYTES {
+            return self.go_away(ENHANCE_YOUR_CALM);
+        }
+
+        if self.pending.len() >= MAX_PENDING {
+            return self.reset(id, REFUSED_STREAM);
+        }
+        match translate(&fields) {
+            Some(request) => {
+                self.windows.insert(id, self.initial_window);
+                self.pending.push_back((id, request));
+                Ok(())
+            }
+            None => self.reset(id, PROTOCOL_ERROR),
+        }
+    }
+
+    /// Accepts part of the current stream's response, as HTTP/1.1.
+    fn respond(&mut self, data: &[u8]) -> Result<()> {
+        let mut current = match self.current.take() {
+            Some(c) => c,
+            None => return Err(HttpError::ConnectionClosed),
+        };
+        let result = self.respond_to(&mut current, data);
+        self.current = Some(current);
+        result
+    }
+
+    fn respond_to(&mut self, current: &mut Current, data: &[u8]) -> Result<()> {
+        if self.dead || !self.windows.contains_key(&current.id) {
+            return Err(HttpError::ConnectionClosed);
+        }
+
+        match current.head.take() {
+            Some(mut head) => {
+                head.extend_from_slice(data);
+                match head.windows(4).position(|w| w == b"\r\n\r\n") {
+                    Some(end) => {
+                        current.body = head.split_off(end + 4);
+                        head.truncate(end);
+                        self.send_head(current.id, &head)?;
+                    }
+                    None => {
+                        current.head = Some(head);
+                        return Ok(());
+                    }
+                }
+            }
+            None => current.body.extend_from_slice(data),
+        }
+
+        // Send whatever fills whole frames, and hold on to the rest.
+        let full =
+            current.body.len() - current.body.len() % self.max_frame_size;
+        if full > 0 {
+            let rest = current.body.split_off(full);
+            self.send_data(current.id, &current.body, false)?;
+            current.body = rest;
+        }
+        Ok(())
+    }
+
+    /// Translates an HTTP/1.1 response head into HEADERS.
+    fn send_head(&mut self, id: u32, head: &[u8]) -> Result<()> {
+        let mut lines = head
+            .split(|&b| b == b'\n')
+            .map(|line| line.strip_suffix(b"\r").unwrap_or(line));
+        let status = lines
+            .next()
+            .and_then(|line| line.split(|&b| b == b' ').nth(1))
+            .ok_or(HttpError::ConnectionClosed)?;
+
+        let mut block = Vec::new();
+        encode_field(&mut block, b":status", status);
+        for line in lines {
+            let colon = match line.iter().position(|&b| b == b':') {
+                Some(i) => i,
+                None => continue,
+            };
+            let name = line[..colon].to_ascii_lowercase();
+            if !CONNECTION_SPECIFIC.contains(&&name[..]) {
+                encode_field(&mut block, &name, trim_ws(&line[colon + 1..]));
+            }
+        }
+
+        let mut chunks = block.chunks(self.max_frame_size).peekable();
+        let mut kind = HEADERS;
+        while let Some(chunk) = chunks.next() {
+            let flags = if chunks.peek().is_none() {
+                END_HEADERS
+            } else {
+                0
+            };
+            self.write_frame(kind, flags, id, chunk)?;
+            kind = CONTINUATION;
+        }
+        Ok(())
+    }
+
+    /// Sends `data` on stream `id`, as fast as the client's flow control
+    /// windows allow.
+    fn send_data(&mut self, id: u32, mut data: &[u8], end: bool) -> Result<()> {
+        loop {
+            let window = match self.windows.get(&id) {
+                Some(&w) => w.min(self.window).max(0) as usize,
+                // Reset while we waited.
+                None => return Err(HttpError::ConnectionClosed),
+            };
+            let n = data.len().min(self.max_frame_size).min(window);
+            if n == 0 && !data.is_empty() {
+                // Wait for the client to make room.
+                let frame = self.read_frame()?;
+                self.handle(frame)?;
+                continue;
+            }
+
+            let last = n == data.len();
+            let flags = if end && last { END_STREAM } else { 0 };
+            self.write_frame(DATA, flags, id, &data[..n])?;
+            self.window -= n as i64;
+            if let Some(w) = self.windows.get_mut(&id) {
+                *w -= n as i64;
+            }
+            data = &data[n..];
+            if last {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Finishes the current stream: cleanly, if it was `served`, or by
+    /// resetting it if its response was cut short.
+    fn end_stream(&mut self, served: bool) -> Result<()> {
+        if let Some(current) = self.current.take() {
+            if self.windows.contains_key(&current.id) {
+                if served && current.head.is_none() {
+                    self.send_data(current.id, &current.body, true)?;
+                } else {
+                    self.reset(current.id, INTERNAL_ERROR)?;
+                }
+                self.windows.remove(&current.id);
+            }
+        }
+
+        if self.dead {
+            Err(HttpError::ConnectionClosed)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Abandons stream `id`.
+    fn reset(&mut self, id: u32, code: u32) -> Result<()> {
+        self.windows.remove(&id);
+        self.pending.retain(|&(p, _)| p != id);
+        self.write_frame(RST_STREAM, 0, id, &code.to_be_bytes())
+    }
+
+    /// Ends the connection, telling the client why.  This always returns
+    /// `ConnectionClosed`, for the caller to pass on.
+    fn go_away<T>(&mut self, code: u32) -> Result<T> {
+        if !self.dead {
+            let mut payload = self.last_stream.to_be_bytes().to_vec();
+            payload.extend_from_slice(&code.to_be_bytes());
+            let _ = self
+                .write_frame(GOAWAY, 0, 0, &payload)
+                .and_then(|_| self.con.flush_output());
+            self.dead = true;
+        }
+        Err(HttpError::ConnectionClosed)
+    }
+
+    fn write_frame(
+        &mut self,
+        kind: u8,
+        flags: u8,
+        stream: u32,
+        payload: &[u8],
+    ) -> Result<()> {
+        if self.dead {
+            return Err(HttpError::ConnectionClosed);
+        }
+
+        let length = (payload.len() as u32).to_be_bytes();
+        let mut header = [0; 9];
+        header[..3].copy_from_slice(&length[1..]);
+        header[3] = kind;
+        header[4] = flags;
+        header[5..].copy_from_slice(&stream.to_be_bytes());
+
+        let result = self
+            .con
+            .write(&header)
+            .and_then(|_| self.con.write(payload));
+        if result.is_err() {
+            self.dead = true;
+        }
+        result
+    }
+}
+
+/// Extracts the header block fragment from a HEADERS frame, without any
+/// padding or priority information.
+fn header_block(frame: &Frame) -> Option<&[u8]> {
+    let mut block = &frame.payload[..];
+    if frame.flags & PADDED != 0 {
+        let (&padding, rest) = block.split_first()?;
+        block = rest.get(..rest.len().checked_sub(padding as usize)?)?;
+    }
+    if frame.flags & PRIORITY != 0 {
+        block = block.get(5..)?;
+    }
+    Some(block)
+}
+
+/// Rewrites a request's header fields as the equivalent HTTP/1.1 request, or
+/// returns `None` if they're malformed.
+fn translate(fields: &[(Vec<u8>, Vec<u8>)]) -> Option<Vec<u8>> {
+    let mut method = None;
+    let mut scheme = None;
+    let mut path = None;
+    let mut authority = None;
+    let mut headers = Vec::new();
+
+    for (name, value) in fields {
+        // Line breaks would let a client smuggle in headers of its choosing.
+        if value.iter().any(|&b| b == b'\r' || b == b'\n' || b == 0) {
+            return None;
+        }
+
+        if name.starts_with(b":") {
+            // Pseudo-headers come first, and only once each.
+            let slot = match &name[..] {
+                b":method" => &mut method,
+                b":scheme" => &mut scheme,
+                b":path" => &mut path,
+                b":authority" => &mut authority,
+                _ => return None,
+            };
+            if !headers.is_empty() || slot.replace(value).is_some() {
+                return None;
+            }
+        } else {
+            if name.is_empty()
+                || !name.iter().all(|&b| is_token_char(b))
+                || name.iter().any(u8::is_ascii_uppercase)
+                || CONNECTION_SPECIFIC.contains(&&name[..])
+            {
+                return None;
+            }
+            headers.push((name, value));
+        }
+    }
+
+    let (method, path) = (method?, path?);
+    scheme?;
+    if method.is_empty()
+        || path.is_empty()
+        || method
+            .iter()
+            .chain(path.iter())
+            .any(|&b| b <= b' ' || b == 0x7F)
+    {
+        return None;
+    }
+
+    let mut request = Vec::new();
+    request.extend_from_slice(method);
+    request.push(b' ');
+    request.extend_from_slice(path);
+    request.extend_from_slice(b" HTTP/1.1\r\n");
+    if let Some(authority) = authority {
+        request.extend_from_slice(b"host: ");
+        request.extend_from_slice(authority);
+        request.extend_from_slice(b"\r\n");
+    }
+    for (name, value) in headers {
+        // :authority takes the place of Host.
+        if authority.is_some() && &name[..] == b"host" {
+            continue;
+        }
+        request.extend_from_slice(name);
+        request.extend_from_slice(b": ");
+        request.extend_from_slice(value);
+        request.extend_from_slice(b"\r\n");
+    }
+    request.extend_from_slice(b"\r\n");
+    Some(request)
+}
+
+fn is_token_char(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+/// Appends a header field to an HPACK block, as a literal that the client
+/// shouldn't index.  Keeping the dynamic table out of it saves us tracking
+/// its state, at some cost in compression.
+fn encode_field(block: &mut Vec<u8>, name: &[u8], value: &[u8]) {
+    block.push(0);
+    encode_string(block, name);
+    encode_string(block, value);
+}
+
+/// Appends an HPACK string literal, without Huffman coding.
+fn encode_string(block: &mut Vec<u8>, s: &[u8]) {
+    // The length has a seven-bit prefix, continued in base 128.
+    let mut n = s.len();
+    if n < 0x7F {
+        block.push(n as u8);
+    } else {
+        block.push(0x7F);
+        n -= 0x7F;
+        while n >= 0x80 {
+            block.push(n as u8 | 0x80);
+            n >>= 7;
+        }
+        block.push(n as u8);
+    }
+    block.extend_from_slice(s);
+}
+
+/// Where a stream's response goes: to the session, for translation.
+struct StreamOutput(Rc<RefCell<Session>>);
+
+impl Write for StreamOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .borrow_mut()
+            .respond(buf)
+            .map(|_| buf.len())
+            .map_err(|_| io::ErrorKind::BrokenPipe.into())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // The session sends the rest when the stream ends, and flushes the
+        // connection before it waits for anything.
+        Ok(())
+    }
+}
+
+impl Output for StreamOutput {}
+
+/// Where a stream's log goes: to the connection's.
+struct SessionLog(Rc<RefCell<Session>>);
+
+impl Write for SessionLog {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().con.write_log(buf).map(|_| buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use loona_hpack::Encoder;
+    use std::fs;
+
+    type Headers = Vec<(Vec<u8>, Vec<u8>)>;
+
+    fn frame(kind: u8, flags: u8, stream: u32, payload: &[u8]) -> Vec<u8> {
+        let mut f = (payload.len() as u32).to_be_bytes()[1..].to_vec();
+        f.push(kind);
+        f.push(flags);
+        f.extend_from_slice(&stream.to_be_bytes());
+        f.extend_from_slice(payload);
+        f
+    }
+
+    fn parse_frames(mut data: &[u8]) -> Vec<Frame> {
+        let mut frames = Vec::new();
+        while !data.is_empty() {
+            let length = u32::from_be_bytes([0, data[0], data[1], data[2]]);
+            let end = 9 + length as usize;
+            frames.push(Frame {
+                kind: data[3],
+                flags: data[4],
+                stream: u32::from_be_bytes([
+                    data[5], data[6], data[7], data[8],
+                ]),
+                payload: data[9..end].to_vec(),
+            });
+            data = &data[end..];
+        }
+        frames
+    }
+
+    /// Runs a connection that starts with the preface and `frames`, returning
+    /// the frames sent in reply.
+    fn exchange(frames: &[Vec<u8>]) -> Vec<Frame> {
+        let mut input = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n".to_vec();
+        for f in frames {
+            input.extend_from_slice(f);
+        }
+        let (c, output, _) = Connection::in_memory(&input);
+        server::serve(c).unwrap();
+        parse_frames(&output.contents())
+    }
+
+    fn request(
+        encoder: &mut Encoder,
+        stream: u32,
+        method: &str,
+        path: &str,
+    ) -> Vec<u8> {
+        let block = encoder.encode(vec![
+            (&b":method"[..], method.as_bytes()),
+            (b":scheme", b"http"),
+            (b":path", path.as_bytes()),
+            (b":authority", b"src"),
+        ]);
+        frame(HEADERS, END_HEADERS | END_STREAM, stream, &block)
+    }
+
+    fn response(frames: &[Frame], stream: u32) -> (Headers, Vec<u8>) {
+        let mut decoder = Decoder::new();
+        let mut headers = Vec::new();
+        let mut body = Vec::new();
+        for f in frames.iter().filter(|f| f.stream == stream) {
+            match f.kind {
+                HEADERS => headers = decoder.decode(&f.payload).unwrap(),
+                DATA => body.extend_from_slice(&f.payload),
+                _ => panic!("unexpected frame type {}", f.kind),
+            }
+        }
+        let end = frames.iter().rfind(|f| f.stream == stream).unwrap();
+        assert_eq!((end.kind, end.flags), (DATA, END_STREAM));
+        (headers, body)
+    }
+
+    #[test]
+    fn test_translate() {
+        let field =
+            |n: &str, v: &str| (n.as_bytes().to_vec(), v.as_bytes().to_vec());
+        let good = vec![
+            field(":method", "GET"),
+            field(":scheme", "https"),
+            field(":authority", "example.com"),
+            field(":path", "/a?b"),
+            field("host", "ignored"),
+            field("if-none-match", "\"x\""),
+        ];
+        assert_eq!(
+            translate(&good).unwrap(),
+            b"GET /a?b HTTP/1.1\r\nhost: example.com\r\nif-none-match: \"x\"\r\n\r\n"
+                .to_vec()
+        );
+
+        let mut bad = good.clone();
+        bad.push(field("X-Upper", "1"));
+        assert_eq!(translate(&bad), None);
+        let mut bad = good.clone();
+        bad.push(field("range", "bytes=0-1\r\nexpect: x"));
+        assert_eq!(translate(&bad), None);
+        let mut bad = good.clone();
+        bad.push(field(":path", "/again"));
+        assert_eq!(translate(&bad), None);
+        let mut bad = good.clone();
+        bad.push(field("connection", "close"));
+        assert_eq!(translate(&bad), None);
+        assert_eq!(translate(&good[1..]), None);
+        assert_eq!(
+            translate(&[
+                field(":method", "GET"),
+                field(":scheme", "http"),
+                field(":path", "/a b")
+            ]),
+            None
+        );
+    }
+
+    #[test]
+    fn test_serve_streams() {
+        let mut encoder = Encoder::new();
+        let frames = exchange(&[
+            frame(SETTINGS, 0, 0, &[]),
+            request(&mut encoder, 1, "GET", "/main.rs"),
+            request(&mut encoder, 3, "HEAD", "/missing"),
+            frame(PING, 0, 0, b"12345678"),
+        ]);
+
+        assert_eq!(frames[0].kind, SETTINGS);
+        assert_eq!(frames[0].flags, 0);
+        assert!(frames
+            .iter()
+            .any(|f| f.kind == SETTINGS && f.flags == ACK && f.stream == 0));
+        assert!(frames.iter().any(|f| f.kind == PING
+            && f.flags == ACK
+            && f.payload == b"12345678"));
+
+        let (headers, body) = response(&frames, 1);
+        assert_eq!(headers[0], (b":status".to_vec(), b"200".to_vec()));
+        assert!(headers.iter().all(|(n, _)| n != b"connection"));
+        assert_eq!(body, fs::read("src/main.rs").unwrap());
+
+        let (headers, body) = response(&frames, 3);
+        assert_eq!(headers[0], (b":status".to_vec(), b"404".to_vec()));
+        assert!(body.is_empty());
+    }
+
+    #[test]
+    fn test_flow_control() {
+        let mut encoder = Encoder::new();
+        let mut settings = SETTINGS_INITIAL_WINDOW_SIZE.to_be_bytes().to_vec();
+        settings.extend_from_slice(&100u32.to_be_bytes());
+        let frames = exchange(&[
+            frame(SETTINGS, 0, 0, &settings),
+            request(&mut encoder, 1, "GET", "/main.rs"),
+            frame(WINDOW_UPDATE, 0, 1, &1_000_000u32.to_be_bytes()),
+        ]);
+
+        let data: Vec<_> = frames.iter().filter(|f| f.kind == DATA).collect();
+        assert_eq!(data[0].payload.len(), 100);
+        let (_, body) = response(&frames, 1);
+        assert_eq!(body, fs::read("src/main.rs").unwrap());
+    }
+
+    #[test]
+    fn test_protocol_errors() {
+        // Anything before SETTINGS is an error.
+        let frames = exchange(&[frame(PING, 0, 0, b"12345678")]);
+        let last = frames.last().unwrap();
+        assert_eq!(last.kind, GOAWAY);
+        assert_eq!(last.payload[4..], PROTOCOL_ERROR.to_be_bytes());
+
+        // As are even stream numbers.
+        let mut encoder = Encoder::new();
+        let frames = exchange(&[
+            frame(SETTINGS, 0, 0, &[]),
+            request(&mut encoder, 2, "GET", "/main.rs"),
+        ]);
+        assert_eq!(frames.last().unwrap().kind, GOAWAY);
+
+        // Malformed requests only cost the stream.
+        let block = encoder.encode(vec![(&b":method"[..], &b"GET"[..])]);
+        let frames = exchange(&[
+            frame(SETTINGS, 0, 0, &[]),
+            frame(HEADERS, END_HEADERS | END_STREAM, 1, &block),
+            frame(GOAWAY, 0, 0, &[0; 8]),
+        ]);
+        let reset = frames.iter().find(|f| f.kind == RST_STREAM).unwrap();
+        assert_eq!(reset.stream, 1);
+        assert_eq!(frames.last().unwrap().payload[4..], NO_ERROR.to_be_bytes());
+    }
+}
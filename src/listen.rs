@@ -0,0 +1,209 @@
+//! Standalone mode, in which we accept connections ourselves instead of
+//! relying on `tcpserver` or `inetd` to hand them to us.
+//!
+//! Each connection is handled by a forked child process whose stdin and stdout
+//! are the accepted socket, so from the child's perspective nothing differs
+//! from running under `tcpserver`.
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::net::{IpAddr, TcpListener};
+use std::os::unix::io::AsRawFd;
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+use nix::errno::Errno;
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::{dup2, fork, ForkResult, Pid};
+
+/// Caps on how many connections we'll serve at once.
+#[derive(Debug, Copy, Clone)]
+pub struct Limits {
+    /// Total simultaneous connections.  Once this many are open, we stop
+    /// accepting until one finishes.
+    pub connections: usize,
+    /// Simultaneous connections from any one address.  Connections beyond
+    /// this are closed immediately.
+    pub per_ip: usize,
+}
+
+/// Listens on `addr` and serves connections until something goes badly wrong.
+///
+/// `handler` runs in a child process for each connection, with the connection
+/// on stdin and stdout, and is passed the peer's address in the format used by
+/// `TCPREMOTEIP`.  The child exits when it returns.
+pub fn run(
+    addr: &str,
+    limits: Limits,
+    handler: impl Fn(String),
+) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    let mut children = Children::new(limits);
+
+    loop {
+        // When we're at capacity, leave new connections in the kernel's queue
+        // until someone leaves.
+        while children.full() {
+            children.reap(true);
+        }
+
+        let (stream, peer) = match listener.accept() {
+            Ok(pair) => pair,
+            // Errors here are typically about the one connection, not the
+            // listener, so keep going -- but if we're out of descriptors or
+            // memory, trying again straight away will only fail again.
+            Err(e) => {
+                let _ = writeln!(io::stderr(), "accept failed: {}", e);
+                let exhausted = matches!(
+                    e.raw_os_error(),
+                    Some(
+                        libc::EMFILE
+                            | libc::ENFILE
+                            | libc::ENOBUFS
+                            | libc::ENOMEM
+                    )
+                );
+                if exhausted {
+                    thread::sleep(Duration::from_millis(100));
+                }
+                continue;
+            }
+        };
+        let ip = peer.ip().to_canonical();
+
+        // Account for anyone who has left while we were waiting.
+        children.reap(false);
+        if !children.admit(ip) {
+            log(ip, b"refused: too many connections from this address");
+            continue;
+        }
+
+        match fork() {
+            Ok(ForkResult::Child) => {
+                drop(listener);
+                let fd = stream.as_raw_fd();
+                if dup2(fd, 0).and_then(|_| dup2(fd, 1)).is_err() {
+                    process::exit(10);
+                }
+                drop(stream);
+
+                handler(ip.to_string());
+                process::exit(0)
+            }
+            Ok(ForkResult::Parent { child }) => children.insert(child, ip),
+            Err(_) => log(ip, b"refused: fork failed"),
+        }
+    }
+}
+
+fn log(ip: IpAddr, message: &[u8]) {
+    // As in `Connection::log`, there's nothing useful to do if this fails.
+    let _ = (|| {
+        let mut err = io::stderr();
+        write!(err, "{} ", ip)?;
+        err.write_all(message)?;
+        err.write_all(b"\n")
+    })();
+}
+
+/// Bookkeeping for the children currently serving connections.
+struct Children {
+    limits: Limits,
+    by_pid: HashMap<Pid, IpAddr>,
+    by_ip: HashMap<IpAddr, usize>,
+}
+
+impl Children {
+    fn new(limits: Limits) -> Self {
+        Children {
+            limits,
+            by_pid: HashMap::new(),
+            by_ip: HashMap::new(),
+        }
+    }
+
+    fn full(&self) -> bool {
+        self.by_pid.len() >= self.limits.connections
+    }
+
+    /// Checks whether another connection from `ip` is within limits.
+    fn admit(&self, ip: IpAddr) -> bool {
+        self.by_ip.get(&ip).copied().unwrap_or(0) < self.limits.per_ip
+    }
+
+    fn insert(&mut self, pid: Pid, ip: IpAddr) {
+        self.by_pid.insert(pid, ip);
+        *self.by_ip.entry(ip).or_insert(0) += 1;
+    }
+
+    fn remove(&mut self, pid: Pid) {
+        if let Some(ip) = self.by_pid.remove(&pid) {
+            if let Some(n) = self.by_ip.get_mut(&ip) {
+                *n -= 1;
+                if *n == 0 {
+                    self.by_ip.remove(&ip);
+                }
+            }
+        }
+    }
+
+    /// Collects exited children.  If `block` is set, waits for at least one.
+    fn reap(&mut self, block: bool) {
+        let mut flags = if block {
+            None
+        } else {
+            Some(WaitPidFlag::WNOHANG)
+        };
+        loop {
+            match waitpid(None, flags) {
+                Ok(WaitStatus::Exited(pid, _))
+                | Ok(WaitStatus::Signaled(pid, _, _)) => self.remove(pid),
+                Ok(WaitStatus::StillAlive) => return,
+                Ok(_) => (),
+                Err(nix::Error::Sys(Errno::EINTR)) => continue,
+                // Most likely ECHILD: there's nobody left to wait for.
+                Err(_) => {
+                    self.by_pid.clear();
+                    self.by_ip.clear();
+                    return;
+                }
+            }
+            // Having reaped one, sweep up any others without waiting.
+            flags = Some(WaitPidFlag::WNOHANG);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_children_limits() {
+        let mut c = Children::new(Limits {
+            connections: 3,
+            per_ip: 2,
+        });
+        let a: IpAddr = "192.0.2.1".parse().unwrap();
+        let b: IpAddr = "2001:db8::1".parse().unwrap();
+
+        assert!(c.admit(a));
+        c.insert(Pid::from_raw(100), a);
+        c.insert(Pid::from_raw(101), a);
+        assert!(!c.admit(a));
+        assert!(c.admit(b));
+        assert!(!c.full());
+
+        c.insert(Pid::from_raw(102), b);
+        assert!(c.full());
+
+        c.remove(Pid::from_raw(100));
+        assert!(c.admit(a));
+        assert!(!c.full());
+
+        // Unknown children are ignored.
+        c.remove(Pid::from_raw(999));
+        assert_eq!(c.by_pid.len(), 2);
+    }
+}
@@ -1,24 +1,158 @@
+use std::ffi::OsString;
+use std::net::IpAddr;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::rc::Rc;
 use std::str::FromStr;
+use std::time::Duration;
 use std::{env, process};
 
+mod access;
+mod aliases;
 mod ascii;
+mod autoindex;
+mod cache_control;
+mod compress;
 mod con;
+mod config;
 mod error;
+mod etag;
 mod file;
 mod filetype;
+mod headers;
+mod http2;
+mod listen;
 mod path;
 mod percent;
+mod range;
+mod redirects;
 mod request;
 mod response;
 mod server;
 mod timeout;
+#[cfg(feature = "tls")]
+mod tls;
 mod unix;
 
+/// What we need to terminate TLS ourselves.
+#[cfg(feature = "tls")]
+type TlsConfig = std::sync::Arc<rustls::ServerConfig>;
+/// Without TLS support, there's never any configuration for it.
+#[cfg(not(feature = "tls"))]
+type TlsConfig = std::convert::Infallible;
+
+/// Serves a single connection on stdin/stdout, as under `tcpserver` -- or, if
+/// `LISTEN` is set, accepts connections on that address and serves each in a
+/// child process.
+pub fn main() {
+    let config = load_config();
+    let tls = load_tls(&config);
+
+    match env::var("LISTEN") {
+        Ok(addr) => {
+            let limits = listen::Limits {
+                connections: env_count("MAX_CONNECTIONS", 100),
+                per_ip: env_count("MAX_PER_IP", 10),
+            };
+            listen::run(&addr, limits, |remote| {
+                serve(remote, tls.as_ref(), &config)
+            })
+            .unwrap_or_else(|_| process::exit(10))
+        }
+        Err(_) => {
+            let remote =
+                env::var("TCPREMOTEIP").unwrap_or_else(|_| "0".to_string());
+            serve(remote, tls.as_ref(), &config)
+        }
+    }
+}
+
+/// Reads the settings for serving requests, so that a mistake in any of them
+/// stops us before we serve anything.
+fn load_config() -> config::Config {
+    let mut config = config::Config::default();
+    with_env_var("UID", |uid| {
+        config.uid = Some(nix::unistd::Uid::from_raw(uid));
+        Ok::<(), ()>(())
+    });
+    with_env_var("GID", |gid| {
+        config.gid = Some(nix::unistd::Gid::from_raw(gid));
+        Ok::<(), ()>(())
+    });
+    let defaults = timeout::Timeouts::default();
+    config.timeouts = timeout::Timeouts {
+        read: env_seconds("READ_TIMEOUT", defaults.read),
+        request: env_seconds("REQUEST_TIMEOUT", defaults.request),
+        idle: env_seconds("IDLE_TIMEOUT", defaults.idle),
+        write: env_seconds("WRITE_TIMEOUT", defaults.write),
+    };
+    with_env_var("LOGFORMAT", |format: access::Format| {
+        config.log_format = Some(format);
+        Ok::<(), ()>(())
+    });
+    if let Some(names) = env::var_os("INDEX") {
+        config.index_names = names
+            .as_bytes()
+            .split(u8::is_ascii_whitespace)
+            .filter(|name| !name.is_empty())
+            .map(<[u8]>::to_vec)
+            .collect();
+    }
+    config.autoindex = env_flag("AUTOINDEX");
+    config.compress = env_flag("COMPRESS");
+    config.compress_cache = env::var_os("COMPRESS_CACHE")
+        .filter(|dir| !dir.is_empty())
+        .map(OsString::into_vec);
+    if let Some(host) = env::var_os("DEFAULT_HOST") {
+        let host = server::normalize_host(host.as_bytes());
+        config.default_host = Some(host.unwrap_or_else(|| process::exit(30)));
+    }
+    config.https = match env::var("PROTO").as_deref() {
+        Err(env::VarError::NotPresent) | Ok("") | Ok("http") => false,
+        Ok("https") => true,
+        _ => process::exit(30),
+    };
+    if let Ok(proxies) = env::var("TRUSTED_PROXIES") {
+        config.trusted_proxies = proxies
+            .split_whitespace()
+            .map(|p| p.parse::<IpAddr>().unwrap_or_else(|_| process::exit(30)))
+            .collect();
+    }
+    config
+}
+
+/// Loads TLS certificates from the directory named by `TLSDIR`, if it's set.
+/// This has to happen before `serve` gives up the authority to read them.
+fn load_tls(config: &config::Config) -> Option<TlsConfig> {
+    let dir = env::var_os("TLSDIR")?;
+
+    #[cfg(feature = "tls")]
+    {
+        // Without a root directory, we serve from where we are.
+        let docroot = env::args_os().nth(1).unwrap_or_else(|| ".".into());
+        Some(
+            tls::load(
+                std::path::Path::new(&dir),
+                std::path::Path::new(&docroot),
+                config.default_host.as_deref(),
+            )
+            .unwrap_or_else(|_| process::exit(50)),
+        )
+    }
+
+    // Asked for TLS, but we can't provide it.  Serving plaintext instead
+    // would be a nasty surprise.
+    #[cfg(not(feature = "tls"))]
+    {
+        let _ = (dir, config);
+        process::exit(50)
+    }
+}
+
 /// Discards undesirable authority and calls through to the connection handler.
 /// In this case, "undesirable authority" means:
 /// - The global filesystem root (shed via `chroot`)
 /// - The calling uid/gid and supplementary groups.
-pub fn main() {
+fn serve(remote: String, tls: Option<&TlsConfig>, config: &config::Config) {
     // Only chroot if a root directory is provided.  This allows for testing (most
     // of the) the daemon as an unprivileged user.
     if let Some(root) = env::args().nth(1) {
@@ -28,20 +162,69 @@ pub fn main() {
             .unwrap_or_else(|n| process::exit(n));
     }
 
-    with_env_var("UID", |uid| {
-        let uid = nix::unistd::Uid::from_raw(uid);
-        nix::unistd::setuid(uid)
-    });
-    with_env_var("GID", |gid| {
-        let gid = nix::unistd::Gid::from_raw(gid);
+    if let Some(uid) = config.uid {
+        nix::unistd::setuid(uid).unwrap_or_else(|_| process::exit(30));
+    }
+    if let Some(gid) = config.gid {
         #[cfg(not(any(target_os = "ios", target_os = "macos")))]
-        nix::unistd::setgroups(&[gid])?;
-        nix::unistd::setgid(gid)
-    });
+        nix::unistd::setgroups(&[gid]).unwrap_or_else(|_| process::exit(30));
+        nix::unistd::setgid(gid).unwrap_or_else(|_| process::exit(30));
+    }
+
+    let mut c = match tls {
+        None => con::Connection::stdio(remote, config.timeouts),
+        #[cfg(feature = "tls")]
+        Some(tls_config) => tls::connection(
+            tls_config.clone(),
+            unix::stdin(),
+            unix::stdout(),
+            Box::new(unix::stderr()),
+            remote,
+            config.timeouts,
+        )
+        .unwrap_or_else(|_| process::exit(50)),
+        #[cfg(not(feature = "tls"))]
+        Some(never) => match *never {},
+    };
+
+    c.set_log_format(config.log_format);
+    c.set_secure(tls.is_some());
+    c.set_config(Rc::new(config.clone()));
 
-    let remote = env::var("TCPREMOTEIP").unwrap_or_else(|_| "0".to_string());
+    server::serve(c).unwrap_or_else(|_| process::exit(40))
+}
+
+/// Checks whether an environment variable is set to anything.
+fn env_flag(var: &str) -> bool {
+    env::var_os(var).is_some_and(|v| !v.is_empty())
+}
+
+/// Reads a number of seconds from an environment variable, if it's set.  A
+/// timeout of zero would mean none at all, so it's refused.
+fn env_seconds(var: &str, default: Duration) -> Duration {
+    match env_or(var, default.as_secs()) {
+        0 => process::exit(30),
+        secs => Duration::from_secs(secs),
+    }
+}
 
-    server::serve(remote).unwrap_or_else(|_| process::exit(40))
+/// Reads a limit from an environment variable, if it's set.  None of ours can
+/// sensibly be zero.
+fn env_count(var: &str, default: usize) -> usize {
+    match env_or(var, default) {
+        0 => process::exit(30),
+        n => n,
+    }
+}
+
+/// Reads a value from an environment variable, if it's set.
+fn env_or<V: FromStr>(var: &str, default: V) -> V {
+    let mut value = default;
+    with_env_var(var, |v| {
+        value = v;
+        Ok::<(), ()>(())
+    });
+    value
 }
 
 fn with_env_var<V: FromStr, E>(var: &str, f: impl FnOnce(V) -> Result<(), E>) {
@@ -1,5 +1,7 @@
 //! URL percent-encoding.
 
+use std::io::Write;
+
 use crate::error::{HttpError, Result};
 
 /// Decodes URL percent-escaping, in-place.  Fails if the encoding is bad.
@@ -40,6 +42,30 @@ pub fn unescape(path: &[u8], out: &mut Vec<u8>) -> Result<()> {
     Ok(())
 }
 
+/// Percent-escapes a path for use in a URL, leaving alone the characters that
+/// can appear in a path as they are.
+pub fn escape(path: &[u8], out: &mut Vec<u8>) {
+    for &b in path {
+        if b.is_ascii_alphanumeric() || b"-._~!$&'()*+,;=:@/".contains(&b) {
+            out.push(b);
+        } else {
+            let _ = write!(out, "%{:02X}", b);
+        }
+    }
+}
+
+/// Percent-escapes a query string for use in a URL.  Escapes the client sent
+/// are kept as they are; only what can't appear in a URL at all is escaped.
+pub fn escape_query(query: &[u8], out: &mut Vec<u8>) {
+    for &b in query {
+        if b.is_ascii_graphic() && b != b'#' {
+            out.push(b);
+        } else {
+            let _ = write!(out, "%{:02X}", b);
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -66,4 +92,15 @@ mod tests {
         unescape_case!(b"foo%X", FAIL);
         unescape_case!(b"foo%", FAIL);
     }
+
+    #[test]
+    fn test_escape() {
+        let mut out = Vec::new();
+        escape(b"/a b/%?#\r\n/:x@y", &mut out);
+        assert_eq!(out, b"/a%20b/%25%3F%23%0D%0A/:x@y".to_vec());
+
+        out.clear();
+        escape_query(b"a=%41&b=c d\r\n#", &mut out);
+        assert_eq!(out, b"a=%41&b=c%20d%0D%0A%23".to_vec());
+    }
 }
@@ -0,0 +1,217 @@
+//! Byte range requests (RFC 7233).
+
+use crate::ascii::AsciiPrefix;
+use crate::request::trim_ws;
+
+/// The most ranges we'll honor in a single request.  Clients asking for more
+/// than this get the whole resource instead, which the standard permits; this
+/// caps the amount of framing overhead a client can make us generate.
+const MAX_RANGES: usize = 16;
+
+/// A single byte-range-spec from a `Range` header, before it has been
+/// resolved against the length of any particular resource.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Spec {
+    /// `first-last`, or `first-` if the last byte is omitted.
+    FromTo(u64, Option<u64>),
+    /// `-n`: the final `n` bytes of the resource.
+    Suffix(u64),
+}
+
+/// A satisfiable range within a resource of known length.  Both ends are
+/// inclusive, as in `Content-Range`.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct ByteRange {
+    pub first: u64,
+    pub last: u64,
+}
+
+impl ByteRange {
+    /// Number of bytes covered by the range.
+    pub fn len(&self) -> u64 {
+        self.last - self.first + 1
+    }
+}
+
+/// The outcome of applying a `Range` header to a particular resource.
+#[derive(Debug, PartialEq)]
+pub enum Selection {
+    /// Send the entire resource, as though no ranges were requested.
+    Whole,
+    /// Send the given ranges, in the order the client asked for them.
+    Partial(Vec<ByteRange>),
+    /// None of the requested ranges overlap the resource.
+    Unsatisfiable,
+}
+
+/// Parses the value of a `Range` header.  Returns `None` if the header is
+/// syntactically invalid or uses a unit other than bytes, in which case the
+/// standard asks us to ignore it.
+pub fn parse(value: &[u8]) -> Option<Vec<Spec>> {
+    if !value.starts_with_ignore_ascii_case(b"bytes=") {
+        return None;
+    }
+
+    let mut specs = Vec::new();
+    // Empty list elements are permitted by the list syntax, so skip them.
+    for spec in value[6..].split(|&b| b == b',').map(trim_ws) {
+        if spec.is_empty() {
+            continue;
+        }
+
+        let dash = spec.iter().position(|&b| b == b'-')?;
+        let (first, last) = (&spec[..dash], &spec[dash + 1..]);
+
+        specs.push(if first.is_empty() {
+            Spec::Suffix(parse_u64(last)?)
+        } else {
+            let first = parse_u64(first)?;
+            let last = if last.is_empty() {
+                None
+            } else {
+                Some(parse_u64(last)?)
+            };
+            if last.is_some_and(|l| l < first) {
+                return None;
+            }
+            Spec::FromTo(first, last)
+        });
+    }
+
+    if specs.is_empty() {
+        None
+    } else {
+        Some(specs)
+    }
+}
+
+impl Spec {
+    /// Resolves this spec against a resource of `length` bytes, producing the
+    /// range actually covered, or `None` if it covers nothing.
+    pub fn resolve(self, length: u64) -> Option<ByteRange> {
+        if length == 0 {
+            return None;
+        }
+        match self {
+            Spec::FromTo(first, last) if first < length => Some(ByteRange {
+                first,
+                last: last.map_or(length - 1, |l| l.min(length - 1)),
+            }),
+            Spec::FromTo(..) => None,
+            Spec::Suffix(0) => None,
+            Spec::Suffix(n) => Some(ByteRange {
+                first: length.saturating_sub(n),
+                last: length - 1,
+            }),
+        }
+    }
+}
+
+/// Decides how to answer a request for `specs` in a resource of `length`
+/// bytes.
+///
+/// Unsatisfiable specs are dropped.  Requests for too many ranges, or for
+/// overlapping ranges, are answered with the whole resource: there's no good
+/// reason for a client to send them, and they make us do extra work.
+pub fn select(specs: &[Spec], length: u64) -> Selection {
+    if specs.len() > MAX_RANGES {
+        return Selection::Whole;
+    }
+
+    let ranges: Vec<_> =
+        specs.iter().filter_map(|s| s.resolve(length)).collect();
+    if ranges.is_empty() {
+        return Selection::Unsatisfiable;
+    }
+
+    let mut sorted = ranges.clone();
+    sorted.sort_by_key(|r| r.first);
+    if sorted.windows(2).any(|w| w[1].first <= w[0].last) {
+        return Selection::Whole;
+    }
+
+    Selection::Partial(ranges)
+}
+
+fn parse_u64(digits: &[u8]) -> Option<u64> {
+    if digits.is_empty() {
+        return None;
+    }
+    digits.iter().try_fold(0u64, |acc, &b| match b {
+        b'0'..=b'9' => acc.checked_mul(10)?.checked_add(u64::from(b - b'0')),
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        use Spec::*;
+
+        assert_eq!(parse(b"bytes=0-499"), Some(vec![FromTo(0, Some(499))]));
+        assert_eq!(parse(b"BYTES=500-"), Some(vec![FromTo(500, None)]));
+        assert_eq!(parse(b"bytes=-500"), Some(vec![Suffix(500)]));
+        assert_eq!(
+            parse(b"bytes=0-0, ,-1,9-"),
+            Some(vec![FromTo(0, Some(0)), Suffix(1), FromTo(9, None)]),
+        );
+
+        assert_eq!(parse(b"bytes="), None);
+        assert_eq!(parse(b"bytes=5-4"), None);
+        assert_eq!(parse(b"bytes=a-b"), None);
+        assert_eq!(parse(b"bytes=-"), None);
+        assert_eq!(parse(b"bytes=1"), None);
+        assert_eq!(parse(b"items=0-1"), None);
+        assert_eq!(parse(b"bytes=0-99999999999999999999"), None);
+    }
+
+    #[test]
+    fn test_resolve() {
+        use Spec::*;
+
+        let r = |first, last| Some(ByteRange { first, last });
+        assert_eq!(FromTo(0, Some(9)).resolve(100), r(0, 9));
+        assert_eq!(FromTo(90, Some(200)).resolve(100), r(90, 99));
+        assert_eq!(FromTo(90, None).resolve(100), r(90, 99));
+        assert_eq!(FromTo(100, None).resolve(100), None);
+        assert_eq!(Suffix(10).resolve(100), r(90, 99));
+        assert_eq!(Suffix(1000).resolve(100), r(0, 99));
+        assert_eq!(Suffix(0).resolve(100), None);
+        assert_eq!(Suffix(10).resolve(0), None);
+    }
+
+    #[test]
+    fn test_select() {
+        use Spec::*;
+
+        assert_eq!(
+            select(&[FromTo(10, Some(19)), FromTo(0, Some(4))], 100),
+            Selection::Partial(vec![
+                ByteRange {
+                    first: 10,
+                    last: 19
+                },
+                ByteRange { first: 0, last: 4 },
+            ]),
+        );
+        // Unsatisfiable parts are dropped...
+        assert_eq!(
+            select(&[FromTo(200, None), Suffix(1)], 100),
+            Selection::Partial(vec![ByteRange {
+                first: 99,
+                last: 99
+            }]),
+        );
+        // ...unless there's nothing left.
+        assert_eq!(select(&[FromTo(200, None)], 100), Selection::Unsatisfiable);
+        // Overlaps get the whole thing.
+        assert_eq!(
+            select(&[FromTo(0, None), Suffix(10)], 100),
+            Selection::Whole
+        );
+        assert_eq!(select(&[Suffix(1); 17], 100), Selection::Whole);
+    }
+}
@@ -0,0 +1,332 @@
+//! Redirects and rewrites, from a `.redirects` file at the top of each
+//! virtual host's directory.
+//!
+//! Each line of the file is an action, a pattern and a target:
+//!
+//! ```text
+//! # Moved for good.
+//! 301      /old.html         /new.html
+//! # Everything under /blog/, to the same place under /posts/.
+//! 308      /blog/*           /posts/*
+//! 302      ~^/u/([0-9]+)$    https://users.example.com/$1
+//! # Serve another file in place of this one, without telling the client.
+//! rewrite  /latest.tar.gz    /releases/1.2.tar.gz
+//! ```
+//!
+//! The action is a redirect status -- 301, 302, 307 or 308 -- or `rewrite`.
+//!
+//! A pattern is matched against the path the client asked for, decoded and
+//! sanitized as it would be to find the file, and without any query.  A plain
+//! pattern has to match the whole path.  One ending in `*` matches any path
+//! starting with the rest of it, and a `*` in the target stands for whatever
+//! followed.  (These are sanitized like requests, so a rule for
+//! `/.well-known/*` works as written.)  One starting with `~` is a regular
+//! expression, and `$1` through `$9` in the target stand for its groups.
+//!
+//! A redirect's target is a path on the same host or a full URL, and the
+//! client's query goes along unless the target has one of its own.  A
+//! rewrite's target is a path, whose file is served in the requested one's
+//! place; rules aren't consulted again for it.
+//!
+//! The first matching rule wins.  Rules we can't make sense of are logged and
+//! skipped.  The file is read when a connection first needs it, so changes
+//! apply from the next connection on.
+
+use std::cell::RefCell;
+
+use regex::bytes::Regex;
+
+use crate::con::Connection;
+use crate::file;
+use crate::path;
+use crate::percent;
+use crate::response::Redirect;
+
+/// What the rules say to do with a request.
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    /// Send the client to a path on this host, or to a full URL.
+    Redirect(Redirect, Vec<u8>),
+    /// Serve the file at this path instead.
+    Rewrite(Vec<u8>),
+}
+
+enum Action {
+    Redirect(Redirect),
+    Rewrite,
+}
+
+enum Pattern {
+    Exact(Vec<u8>),
+    Prefix(Vec<u8>),
+    Regex(Regex),
+}
+
+struct Rule {
+    action: Action,
+    pattern: Pattern,
+    target: Vec<u8>,
+}
+
+thread_local! {
+    /// Each host's rules, by directory.
+    static RULES: file::ConfigCache<Vec<Rule>> = RefCell::default();
+}
+
+/// Consults `root`'s rules about `path`.  `query` is the request's query
+/// string, still escaped, if it had one.
+pub fn lookup(
+    con: &mut Connection,
+    root: &[u8],
+    path: &[u8],
+    query: Option<&[u8]>,
+) -> Option<Outcome> {
+    let rules = file::cached(&RULES, root, || load(con, root));
+    find(&rules, path, query)
+}
+
+/// Reads `root`'s rules, logging any we have to skip.
+fn load(con: &mut Connection, root: &[u8]) -> Vec<Rule> {
+    let mut rules_path = root.to_vec();
+    rules_path.extend_from_slice(b"/.redirects");
+    let contents = match file::read_config(&rules_path) {
+        Some(contents) => contents,
+        None => return Vec::new(),
+    };
+    parse(&contents, |line, why| {
+        let mut msg = b"skipping `".to_vec();
+        // Keep the log to a line per entry, whatever's in the file.
+        msg.extend(line.iter().map(|&b| if b < b' ' { b'?' } else { b }));
+        msg.extend_from_slice(b"`: ");
+        msg.extend_from_slice(why.as_bytes());
+        con.log(&rules_path, Some(b"rule"), &msg);
+    })
+}
+
+/// Parses the rules in `contents`, passing each line that isn't one to `bad`
+/// along with what's wrong with it.
+fn parse(contents: &[u8], mut bad: impl FnMut(&[u8], &str)) -> Vec<Rule> {
+    file::config_lines(contents)
+        .filter_map(|line| match parse_rule(line) {
+            Ok(rule) => Some(rule),
+            Err(why) => {
+                bad(line, why);
+                None
+            }
+        })
+        .collect()
+}
+
+fn find(rules: &[Rule], path: &[u8], query: Option<&[u8]>) -> Option<Outcome> {
+    rules.iter().find_map(|rule| {
+        let target = &rule.target[..];
+        let redirecting = matches!(rule.action, Action::Redirect(_));
+
+        // Anything taken from the request has to be escaped before it goes
+        // into a URL, lest it smuggle in a header -- but not before it goes
+        // into a file name.
+        let substitute = |part: &[u8], out: &mut Vec<u8>| {
+            if redirecting {
+                percent::escape(part, out)
+            } else {
+                out.extend_from_slice(part)
+            }
+        };
+
+        let mut result = Vec::new();
+        match rule.pattern {
+            Pattern::Exact(ref p) if p == path => {
+                result.extend_from_slice(target);
+            }
+            Pattern::Prefix(ref p) if path.starts_with(p) => {
+                match target.iter().position(|&b| b == b'*') {
+                    Some(star) => {
+                        result.extend_from_slice(&target[..star]);
+                        substitute(&path[p.len()..], &mut result);
+                        result.extend_from_slice(&target[star + 1..]);
+                    }
+                    None => result.extend_from_slice(target),
+                }
+            }
+            Pattern::Regex(ref re) => {
+                let groups = re.captures(path)?;
+                let mut rest = target;
+                while let Some(dollar) = rest.iter().position(|&b| b == b'$') {
+                    result.extend_from_slice(&rest[..dollar]);
+                    match rest.get(dollar + 1) {
+                        Some(&n @ b'1'..=b'9') => {
+                            if let Some(group) = groups.get((n - b'0').into()) {
+                                substitute(group.as_bytes(), &mut result);
+                            }
+                            rest = &rest[dollar + 2..];
+                        }
+                        _ => {
+                            result.push(b'$');
+                            rest = &rest[dollar + 1..];
+                        }
+                    }
+                }
+                result.extend_from_slice(rest);
+            }
+            _ => return None,
+        }
+
+        Some(match rule.action {
+            Action::Redirect(kind) => {
+                match query {
+                    Some(query) if !result.contains(&b'?') => {
+                        result.push(b'?');
+                        percent::escape_query(query, &mut result);
+                    }
+                    _ => (),
+                }
+                Outcome::Redirect(kind, result)
+            }
+            Action::Rewrite => Outcome::Rewrite(result),
+        })
+    })
+}
+
+fn parse_rule(line: &[u8]) -> Result<Rule, &'static str> {
+    let mut fields = line
+        .split(u8::is_ascii_whitespace)
+        .filter(|f| !f.is_empty());
+    let (action, pattern, target) =
+        match (fields.next(), fields.next(), fields.next(), fields.next()) {
+            (Some(a), Some(p), Some(t), None) => (a, p, t),
+            _ => return Err("not an action, a pattern and a target"),
+        };
+    if !target.iter().all(u8::is_ascii_graphic) {
+        return Err("target has control characters");
+    }
+
+    let action = match action {
+        b"rewrite" if target.starts_with(b"/") => Action::Rewrite,
+        b"rewrite" => return Err("rewrite target isn't a path"),
+        _ if target.starts_with(b"/")
+            || target.starts_with(b"http://")
+            || target.starts_with(b"https://") =>
+        {
+            Action::Redirect(
+                Redirect::from_code(action).ok_or("unknown action")?,
+            )
+        }
+        _ => return Err("redirect target isn't a path or URL"),
+    };
+
+    let pattern = if let Some(re) = pattern.strip_prefix(b"~") {
+        std::str::from_utf8(re)
+            .ok()
+            .and_then(|re| Regex::new(re).ok())
+            .map(Pattern::Regex)
+            .ok_or("bad regular expression")?
+    } else if pattern.starts_with(b"/") {
+        let (mut p, prefix) = match pattern.strip_suffix(b"*") {
+            Some(p) => (p.to_vec(), true),
+            None => (pattern.to_vec(), false),
+        };
+        path::sanitize(&mut p);
+        if prefix {
+            Pattern::Prefix(p)
+        } else {
+            Pattern::Exact(p)
+        }
+    } else {
+        return Err("pattern isn't a path or a regular expression");
+    };
+
+    Ok(Rule {
+        action,
+        pattern,
+        target: target.to_vec(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file::TempDir;
+    use std::fs;
+
+    const RULES: &[u8] = b"# comment\r\n\
+        \n\
+        301 /old.html /new.html\r\n\
+        308 /blog/* /posts/*\n\
+        302 ~^/u/([0-9]+)/(.*)$ https://example.com/$2?id=$1\n\
+        rewrite /latest /releases/1.2.tar.gz\n\
+        rewrite /.well-known/* /well-known/*\n\
+        303 /other /elsewhere\n\
+        rewrite /relative elsewhere\n\
+        301 ~([ /broken\n";
+
+    fn redirect(kind: Redirect, location: &[u8]) -> Option<Outcome> {
+        Some(Outcome::Redirect(kind, location.to_vec()))
+    }
+
+    #[test]
+    fn test_find() {
+        let mut skipped = Vec::new();
+        let rules = parse(RULES, |line, _| skipped.push(line.to_vec()));
+        assert_eq!(
+            skipped,
+            vec![
+                b"303 /other /elsewhere".to_vec(),
+                b"rewrite /relative elsewhere".to_vec(),
+                b"301 ~([ /broken".to_vec(),
+            ]
+        );
+
+        assert_eq!(
+            find(&rules, b"/old.html", None),
+            redirect(Redirect::Moved, b"/new.html")
+        );
+        assert_eq!(
+            find(&rules, b"/old.html", Some(b"a=1")),
+            redirect(Redirect::Moved, b"/new.html?a=1")
+        );
+        assert_eq!(
+            find(&rules, b"/old.html", Some(b"a\rSet-Cookie:x=1")),
+            redirect(Redirect::Moved, b"/new.html?a%0DSet-Cookie:x=1")
+        );
+        assert_eq!(find(&rules, b"/old.html/", None), None);
+        assert_eq!(
+            find(&rules, b"/blog/2020/a b\r\n", None),
+            redirect(Redirect::Permanent, b"/posts/2020/a%20b%0D%0A")
+        );
+        assert_eq!(
+            find(&rules, b"/u/42/x", Some(b"a=1")),
+            redirect(Redirect::Found, b"https://example.com/x?id=42")
+        );
+        assert_eq!(
+            find(&rules, b"/latest", Some(b"a=1")),
+            Some(Outcome::Rewrite(b"/releases/1.2.tar.gz".to_vec()))
+        );
+        assert_eq!(
+            find(&rules, b"/:well-known/a b", None),
+            Some(Outcome::Rewrite(b"/well-known/a b".to_vec()))
+        );
+        assert_eq!(find(&rules, b"/other", None), None);
+        assert_eq!(find(&rules, b"/relative", None), None);
+    }
+
+    #[test]
+    fn test_lookup() {
+        let dir = TempDir::new("redirects");
+        let rules_path = dir.path().join(".redirects");
+        fs::write(&rules_path, b"301 /a /b\n302 ~( /c\n").unwrap();
+        let (mut c, _, log) = Connection::in_memory(b"");
+
+        let first = lookup(&mut c, dir.as_bytes(), b"/a", None);
+        // Once parsed, the rules stick for the life of the process.
+        fs::write(&rules_path, b"301 /a /elsewhere\n").unwrap();
+        let second = lookup(&mut c, dir.as_bytes(), b"/a", None);
+
+        assert_eq!(first, redirect(Redirect::Moved, b"/b"));
+        assert_eq!(second, first);
+        let log = String::from_utf8(log.contents()).unwrap();
+        assert_eq!(log.lines().count(), 1);
+        assert!(log.ends_with(
+            "/.redirects [rule]: skipping `302 ~( /c`: bad regular expression\n"
+        ));
+    }
+}
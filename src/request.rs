@@ -1,8 +1,12 @@
 //! HTTP request support.
 
+use httpdate::HttpDate;
+
 use crate::ascii::AsciiPrefix;
 use crate::con::Connection; // interesting, wildcard doesn't work for this.
 use crate::error::*;
+use crate::etag::Condition;
+use crate::range;
 
 /// Accepts a request from the given `Connection` and returns its contents, or
 /// an error.
@@ -11,6 +15,8 @@ use crate::error::*;
 /// `Connection` can theoretically be kept open after an error, I haven't done
 /// the legwork on this yet.
 pub fn read(c: &mut Connection) -> Result<Request> {
+    c.begin_request()?;
+
     // Take the first non-blank line as the Request-Line (5.1).
     // Our tolerance of multiple blank lines between requests on a connection, and
     // blank lines before the initial request, mimics Publicfile, but does not
@@ -23,6 +29,18 @@ pub fn read(c: &mut Connection) -> Result<Request> {
         }
     };
 
+    // HTTP/2 clients open with a preface that starts out looking like a
+    // request, but isn't one.
+    if request_line == b"PRI * HTTP/2.0" {
+        let rest = (c.readline()?, c.readline()?, c.readline()?);
+        if rest != (vec![], b"SM".to_vec(), vec![]) {
+            return Err(HttpError::BadRequest);
+        }
+        c.end_request();
+        return Err(HttpError::Http2Preface);
+    }
+
+    c.record().request_line = request_line.clone();
     let mut req = parse_request_line(request_line)?;
 
     // Collect headers from the connection.  There is some overlap between the
@@ -33,6 +51,12 @@ pub fn read(c: &mut Connection) -> Result<Request> {
     // multi-line header.
     let mut hdr = Vec::new();
 
+    // Connection options can appear in any order, and "close" trumps
+    // "keep-alive", so we collect them and decide at the end.
+    let mut close = false;
+    let mut saw_host = false;
+    let mut keep_alive = false;
+
     loop {
         let hdr_line = c.readline()?;
 
@@ -54,47 +78,95 @@ pub fn read(c: &mut Connection) -> Result<Request> {
             if hdr.starts_with_ignore_ascii_case(b"expect") {
                 return Err(HttpError::SpanishInquisition);
             }
-            if hdr.starts_with_ignore_ascii_case(b"if-match")
-                || hdr.starts_with_ignore_ascii_case(b"if-unmodified-since")
-            {
-                return Err(HttpError::PreconditionFailed);
-            }
-
-            if hdr.starts_with_ignore_ascii_case(b"host") {
+            if hdr.starts_with_ignore_ascii_case(b"host:") {
+                // A request can only be for one host.
+                if saw_host {
+                    return Err(HttpError::BadRequest);
+                }
+                saw_host = true;
                 // Only accept a host from the request headers if none was provided
-                // in the start line.
-                if req.host.is_none() {
-                    // Just drop whitespace characters from the host header.  This
-                    // questionable interpretation of the spec mimics publicfile.
-                    let new_host = hdr[5..]
-                        .iter()
-                        .filter(|&&b| !is_http_ws(b))
-                        .cloned()
-                        .collect::<Vec<_>>();
-                    if !new_host.is_empty() {
-                        req.host = Some(new_host)
+                // in the start line.  Whether it's any good is checked once it's
+                // used.
+                let new_host = trim_ws(&hdr[5..]);
+                if req.host.is_none() && !new_host.is_empty() {
+                    req.host = Some(new_host.to_vec())
+                }
+            } else if hdr.starts_with_ignore_ascii_case(b"if-modified-since:") {
+                // Invalid dates are ignored, as the spec requires.
+                if req.if_modified_since.is_none() {
+                    req.if_modified_since = parse_date(&hdr[18..]);
+                }
+            } else if hdr.starts_with_ignore_ascii_case(b"if-match:") {
+                if let Some(c) = Condition::parse(&hdr[9..]) {
+                    match req.if_match {
+                        Some(ref mut existing) => existing.merge(c),
+                        None => req.if_match = Some(c),
                     }
                 }
-            } else if hdr.starts_with_ignore_ascii_case(b"if-modified-since") {
-                req.if_modified_since = Some(
-                    hdr[18..]
-                        .iter()
-                        .skip_while(|&&b| is_http_ws(b))
-                        .cloned()
-                        .collect(),
-                );
-            } else if hdr.starts_with_ignore_ascii_case(b"accept-encoding:") {
-                // TODO: our interpretation of this header's values are out of spec,
-                // but identical to publicfile's behavior.  We could get tripped up
-                // by encodings that mention gzip as a substring, or by clients
-                // trying to forbid gzip for some reason ("gzip;q=0" is equivalent
-                // to omitting "gzip", but nobody does this).
-                for window in hdr[16..].windows(4) {
-                    if window.starts_with_ignore_ascii_case(b"gzip") {
-                        req.accept_gzip = true;
-                        break;
+            } else if hdr.starts_with_ignore_ascii_case(b"if-unmodified-since:")
+            {
+                // As with If-Modified-Since, the first copy wins.  Invalid
+                // dates are ignored.
+                if req.if_unmodified_since.is_none() {
+                    req.if_unmodified_since = parse_date(&hdr[20..]);
+                }
+            } else if hdr.starts_with_ignore_ascii_case(b"if-none-match:") {
+                // This one is a list, so repeated headers accumulate.
+                // Malformed values are ignored.
+                if let Some(c) = Condition::parse(&hdr[14..]) {
+                    match req.if_none_match {
+                        Some(ref mut existing) => existing.merge(c),
+                        None => req.if_none_match = Some(c),
                     }
                 }
+            } else if hdr.starts_with_ignore_ascii_case(b"connection:") {
+                for option in hdr[11..].split(|&b| b == b',').map(trim_ws) {
+                    if option.eq_ignore_ascii_case(b"close") {
+                        close = true;
+                    } else if option.eq_ignore_ascii_case(b"keep-alive") {
+                        keep_alive = true;
+                    }
+                }
+            } else if hdr.starts_with_ignore_ascii_case(b"range:") {
+                // Like If-Modified-Since, Range is not a list of independent
+                // tokens, so the first copy wins.  Malformed values are ignored
+                // as required by the spec.
+                if req.range.is_none() {
+                    req.range = range::parse(trim_ws(&hdr[6..]));
+                }
+            } else if hdr.starts_with_ignore_ascii_case(b"if-range:") {
+                if req.if_range.is_none() {
+                    req.if_range = Some(trim_ws(&hdr[9..]).to_vec());
+                }
+            } else if hdr.starts_with_ignore_ascii_case(b"referer:") {
+                // These two are only of interest to the access log.
+                c.record().referer = Some(trim_ws(&hdr[8..]).to_vec());
+            } else if hdr.starts_with_ignore_ascii_case(b"user-agent:") {
+                c.record().user_agent = Some(trim_ws(&hdr[11..]).to_vec());
+            } else if hdr.starts_with_ignore_ascii_case(b"x-forwarded-proto:") {
+                // Each proxy along the way adds to the list, in this header or
+                // another, so only the last entry is our own proxy's word.
+                // Anything before it could have come from the client.
+                let last = hdr[18..]
+                    .rsplit(|&b| b == b',')
+                    .map(trim_ws)
+                    .find(|p| !p.is_empty());
+                if let Some(p) = last {
+                    req.forwarded_tls = if p.eq_ignore_ascii_case(b"https") {
+                        Some(true)
+                    } else if p.eq_ignore_ascii_case(b"http") {
+                        Some(false)
+                    } else {
+                        None
+                    };
+                }
+            } else if hdr.starts_with_ignore_ascii_case(b"accept:") {
+                if accepts(&hdr[7..], b"application/json") {
+                    req.accept_json = true;
+                }
+            } else if hdr.starts_with_ignore_ascii_case(b"accept-encoding:") {
+                // This one is a list, so repeated headers accumulate.
+                req.accept_encoding.add(&hdr[16..]);
             }
 
             // We've processed this header -- discard it.
@@ -108,6 +180,12 @@ pub fn read(c: &mut Connection) -> Result<Request> {
         hdr.extend(hdr_line);
     }
 
+    // HTTP/1.1 connections persist unless the client says otherwise; HTTP/1.0
+    // connections only persist if the client asks.
+    req.keep_alive = !close && (keep_alive || req.protocol == Protocol::Http11);
+    c.record().host = req.host.clone();
+
+    c.end_request();
     Ok(req)
 }
 
@@ -115,6 +193,115 @@ fn is_http_ws(c: u8) -> bool {
     c == b' ' || c == b'\t'
 }
 
+/// Strips leading and trailing whitespace from a header value.
+pub fn trim_ws(value: &[u8]) -> &[u8] {
+    let start = value
+        .iter()
+        .position(|&b| !is_http_ws(b))
+        .unwrap_or(value.len());
+    let end = value
+        .iter()
+        .rposition(|&b| !is_http_ws(b))
+        .map_or(start, |i| i + 1);
+    &value[start..end]
+}
+
+/// Checks whether a list of preferences names `wanted` without giving it a
+/// weight of zero.
+fn accepts(list: &[u8], wanted: &[u8]) -> bool {
+    preferences(list).any(|(v, w)| v.eq_ignore_ascii_case(wanted) && w > 0)
+}
+
+/// Splits a comma-separated list of preferences, as found in `Accept` and its
+/// relatives, into values and their weights in thousandths.  Items with
+/// malformed weights are skipped.
+fn preferences(list: &[u8]) -> impl Iterator<Item = (&[u8], u16)> {
+    list.split(|&b| b == b',').filter_map(|item| {
+        let mut parts = item.split(|&b| b == b';').map(trim_ws);
+        let value = parts.next().filter(|v| !v.is_empty())?;
+        let mut weight = 1000;
+        for param in parts {
+            if param.starts_with_ignore_ascii_case(b"q=") {
+                weight = parse_weight(&param[2..])?;
+            }
+        }
+        Some((value, weight))
+    })
+}
+
+/// Parses a weight (5.3.1): a number from 0 to 1 with at most three decimal
+/// places, which we return in thousandths.
+fn parse_weight(value: &[u8]) -> Option<u16> {
+    let (int, frac) = match value.iter().position(|&b| b == b'.') {
+        Some(i) => (&value[..i], &value[i + 1..]),
+        None => (value, &b""[..]),
+    };
+    if int.len() != 1
+        || frac.len() > 3
+        || !int.iter().chain(frac).all(u8::is_ascii_digit)
+    {
+        return None;
+    }
+
+    let mut weight = u16::from(int[0] - b'0');
+    for i in 0..3 {
+        weight = weight * 10 + frac.get(i).map_or(0, |&d| u16::from(d - b'0'));
+    }
+    if weight > 1000 {
+        None
+    } else {
+        Some(weight)
+    }
+}
+
+/// The content codings a client will accept, from `Accept-Encoding` (5.3.4).
+#[derive(Debug, Default)]
+pub struct AcceptEncoding {
+    /// Lowercased coding names and their weights, in the order given.
+    codings: Vec<(Vec<u8>, u16)>,
+}
+
+impl AcceptEncoding {
+    fn add(&mut self, list: &[u8]) {
+        for (coding, weight) in preferences(list) {
+            let mut coding = coding.to_ascii_lowercase();
+            // An alias from HTTP/1.0 days that we're asked to honor.
+            if coding == b"x-gzip" {
+                coding = b"gzip".to_vec();
+            }
+            self.codings.push((coding, weight));
+        }
+    }
+
+    fn find(&self, coding: &[u8]) -> Option<u16> {
+        self.codings
+            .iter()
+            .find(|(c, _)| c == coding)
+            .map(|&(_, w)| w)
+    }
+
+    /// The client's preference for `coding`, in thousandths.  Zero means it's
+    /// unacceptable, which is the case for anything the client didn't
+    /// mention.
+    pub fn weight(&self, coding: &[u8]) -> u16 {
+        self.find(coding).or_else(|| self.find(b"*")).unwrap_or(0)
+    }
+
+    /// The client's preference for content as-is.  That's acceptable unless
+    /// the client rules it out, but ranks below anything the client named.
+    pub fn identity_weight(&self) -> u16 {
+        self.find(b"identity")
+            .or_else(|| self.find(b"*"))
+            .unwrap_or(1)
+    }
+}
+
+/// Parses an HTTP-date in any of the three formats the spec requires us to
+/// accept.  Returns `None` if the value isn't a valid date.
+fn parse_date(value: &[u8]) -> Option<HttpDate> {
+    std::str::from_utf8(trim_ws(value)).ok()?.parse().ok()
+}
+
 #[derive(Debug, PartialEq, Copy, Clone)]
 pub enum Method {
     Get,
@@ -125,6 +312,9 @@ pub enum Method {
 pub enum Protocol {
     Http10,
     Http11,
+    /// HTTP/2, whose requests reach us translated into HTTP/1.1 by the
+    /// `http2` module.
+    Http2,
 }
 
 fn indexof<T: PartialEq>(slice: &[T], item: T) -> usize {
@@ -142,14 +332,27 @@ fn parse_request_line(line: Vec<u8>) -> Result<Request> {
         b"HEAD" => Method::Head,
         _ => return Err(HttpError::BadMethod),
     };
+    // Control characters have no business in a URL, and would follow the
+    // query into the headers of any redirect we make.
+    if parts[1].iter().any(u8::is_ascii_control) {
+        return Err(HttpError::BadRequest);
+    }
+    // A fragment is only meaningful to the client, which shouldn't have sent
+    // it; ignore it if it did.
+    let raw = &parts[1][..indexof(parts[1], b'#')];
     let (host, mut path) = {
-        let raw = parts[1];
         // Distinguish an old-style path-only request from a HTTP/1.1-style URL
         // request by checking for the presence of an HTTP scheme.
         if raw.starts_with_ignore_ascii_case(b"http://") {
-            // Split the remainder at the first slash.  The bytes to the left are the
-            // host name; to the right, including the delimiter, the path.
-            let (host, path) = raw[7..].split_at(indexof(&raw[7..], b'/'));
+            // Split the remainder at the first slash or question mark.  The
+            // bytes to the left are the host name; to the right, including the
+            // delimiter, the path.
+            let rest = &raw[7..];
+            let end = rest
+                .iter()
+                .position(|&b| b == b'/' || b == b'?')
+                .unwrap_or(rest.len());
+            let (host, path) = rest.split_at(end);
             let path = path.to_vec();
 
             if host.is_empty() {
@@ -161,19 +364,24 @@ fn parse_request_line(line: Vec<u8>) -> Result<Request> {
                 (Some(host.to_vec()), path)
             }
         } else {
-            (None, parts[1].to_vec())
+            (None, raw.to_vec())
         }
     };
+    // The query, if any, isn't part of the path.  It's kept as sent, escapes
+    // and all.
+    let query = path
+        .iter()
+        .position(|&b| b == b'?')
+        .map(|i| path.split_off(i)[1..].to_vec());
     let protocol = match parts[2] {
         b"HTTP/1.0" => Protocol::Http10,
         b"HTTP/1.1" => Protocol::Http11,
         _ => return Err(HttpError::BadProtocol),
     };
 
-    // Slap an 'index.html' onto the end of any path that, from simple textual
-    // inspection, ends in a directory.
-    if path.is_empty() || path.ends_with(b"/") {
-        path.extend_from_slice(b"index.html");
+    // A URL with nothing after the host means the top directory.
+    if path.is_empty() {
+        path.push(b'/');
     }
 
     Ok(Request {
@@ -181,8 +389,17 @@ fn parse_request_line(line: Vec<u8>) -> Result<Request> {
         protocol,
         host,
         path,
-        if_modified_since: None, // Filled in later.
-        accept_gzip: false,      // Filled in later.
+        query,
+        if_modified_since: None,   // Filled in later.
+        if_none_match: None,       // Filled in later.
+        if_match: None,            // Filled in later.
+        if_unmodified_since: None, // Filled in later.
+        accept_encoding: AcceptEncoding::default(), // Filled in later.
+        accept_json: false,        // Filled in later.
+        forwarded_tls: None,       // Filled in later.
+        range: None,               // Filled in later.
+        if_range: None,            // Filled in later.
+        keep_alive: false,         // Filled in later.
     })
 }
 
@@ -192,6 +409,110 @@ pub struct Request {
     pub protocol: Protocol,
     pub host: Option<Vec<u8>>,
     pub path: Vec<u8>,
-    pub if_modified_since: Option<Vec<u8>>,
-    pub accept_gzip: bool,
+    /// Everything after the `?` in the request target, if there was one.
+    pub query: Option<Vec<u8>>,
+    pub if_modified_since: Option<HttpDate>,
+    pub if_none_match: Option<Condition>,
+    pub if_match: Option<Condition>,
+    pub if_unmodified_since: Option<HttpDate>,
+    pub accept_encoding: AcceptEncoding,
+    /// Whether the client asked for JSON, which gets it directory listings in
+    /// that form.
+    pub accept_json: bool,
+    /// Whether `X-Forwarded-Proto` says the client used TLS, if it says.
+    /// This is only to be believed from a trusted proxy.
+    pub forwarded_tls: Option<bool>,
+    /// Byte ranges requested by the client, if any.
+    pub range: Option<Vec<range::Spec>>,
+    /// Validator that must match for `range` to be honored.
+    pub if_range: Option<Vec<u8>>,
+    /// Whether the client is willing to send another request on this
+    /// connection after this one.
+    pub keep_alive: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_request_line() {
+        let parse = |line: &[u8]| parse_request_line(line.to_vec()).unwrap();
+
+        let req = parse(b"GET /app.js?v=123#top HTTP/1.1");
+        assert_eq!(req.path, b"/app.js".to_vec());
+        assert_eq!(req.query, Some(b"v=123".to_vec()));
+
+        let req = parse(b"GET /a%3Fb? HTTP/1.1");
+        assert_eq!(req.path, b"/a%3Fb".to_vec());
+        assert_eq!(req.query, Some(Vec::new()));
+
+        let req = parse(b"HEAD http://Example.com?x=/y HTTP/1.0");
+        assert_eq!(req.host, Some(b"Example.com".to_vec()));
+        assert_eq!(req.path, b"/".to_vec());
+        assert_eq!(req.query, Some(b"x=/y".to_vec()));
+
+        let req = parse(b"GET /#frag?not-a-query HTTP/1.1");
+        assert_eq!(req.path, b"/".to_vec());
+        assert_eq!(req.query, None);
+
+        for line in [
+            &b"GET /d?a\rSet-Cookie:x=1 HTTP/1.1"[..],
+            b"GET /d\n HTTP/1.1",
+            b"GET /d?\0 HTTP/1.1",
+            b"GET /d#\x7f HTTP/1.1",
+        ] {
+            assert!(matches!(
+                parse_request_line(line.to_vec()),
+                Err(HttpError::BadRequest)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_accepts() {
+        assert!(accepts(b" application/json", b"application/json"));
+        assert!(accepts(
+            b"text/html, Application/JSON;q=0.5",
+            b"application/json"
+        ));
+        assert!(!accepts(b"text/html, */*", b"application/json"));
+        assert!(!accepts(b"application/json; q=0.0", b"application/json"));
+        assert!(!accepts(b"application/jsonx", b"application/json"));
+        assert!(!accepts(b"application/json;q=2", b"application/json"));
+    }
+
+    #[test]
+    fn test_parse_weight() {
+        assert_eq!(parse_weight(b"1"), Some(1000));
+        assert_eq!(parse_weight(b"1.000"), Some(1000));
+        assert_eq!(parse_weight(b"0.5"), Some(500));
+        assert_eq!(parse_weight(b"0.125"), Some(125));
+        assert_eq!(parse_weight(b"0."), Some(0));
+        assert_eq!(parse_weight(b"1.001"), None);
+        assert_eq!(parse_weight(b"0.1234"), None);
+        assert_eq!(parse_weight(b".5"), None);
+        assert_eq!(parse_weight(b"x"), None);
+    }
+
+    #[test]
+    fn test_accept_encoding() {
+        let mut ae = AcceptEncoding::default();
+        assert_eq!(ae.weight(b"gzip"), 0);
+        assert_eq!(ae.identity_weight(), 1);
+
+        ae.add(b"GZIP;q=0.8, br");
+        ae.add(b"zstd;q=0, identity;q=0.5");
+        assert_eq!(ae.weight(b"gzip"), 800);
+        assert_eq!(ae.weight(b"br"), 1000);
+        assert_eq!(ae.weight(b"zstd"), 0);
+        assert_eq!(ae.identity_weight(), 500);
+
+        let mut ae = AcceptEncoding::default();
+        ae.add(b"x-gzip, *;q=0.3, bogus;q=high");
+        assert_eq!(ae.weight(b"gzip"), 1000);
+        assert_eq!(ae.weight(b"br"), 300);
+        assert_eq!(ae.weight(b"bogus"), 300);
+        assert_eq!(ae.identity_weight(), 300);
+    }
 }
@@ -1,68 +1,301 @@
 //! HTTP response support.
 
+use std::borrow::Cow;
+use std::ffi;
+use std::fs;
 use std::io;
-use std::io::BufRead;
-use std::time::SystemTime;
+use std::io::{BufRead, Read, Seek};
+use std::os::unix::ffi::OsStrExt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+use httpdate::HttpDate;
+
+use crate::cache_control::Policy;
+use crate::compress;
 use crate::con::Connection;
 use crate::error::{HttpError, Result};
-use crate::file::OpenFile;
-use crate::request::{Method, Protocol};
-
+use crate::etag::EntityTag;
+use crate::file::{self, FileOrDir, OpenFile};
+use crate::filetype;
+use crate::headers::Header;
+use crate::range::{self, ByteRange, Selection};
+use crate::request::{Method, Protocol, Request};
+
+#[derive(Debug, PartialEq, Copy, Clone)]
 pub enum ContentEncoding {
+    Brotli,
+    Zstd,
     Gzip,
 }
 
+impl ContentEncoding {
+    /// Every encoding we can serve, best compression first, which is the
+    /// order we prefer them in when the client has no preference.
+    pub const ALL: [ContentEncoding; 3] = [
+        ContentEncoding::Brotli,
+        ContentEncoding::Zstd,
+        ContentEncoding::Gzip,
+    ];
+
+    /// The name of the encoding in `Content-Encoding` and `Accept-Encoding`.
+    pub fn token(self) -> &'static [u8] {
+        match self {
+            ContentEncoding::Brotli => b"br",
+            ContentEncoding::Zstd => b"zstd",
+            ContentEncoding::Gzip => b"gzip",
+        }
+    }
+
+    /// The extension of files precompressed with this encoding, minus the
+    /// dot.
+    pub fn extension(self) -> &'static [u8] {
+        match self {
+            ContentEncoding::Brotli => b"br",
+            ContentEncoding::Zstd => b"zst",
+            ContentEncoding::Gzip => b"gz",
+        }
+    }
+}
+
+/// How a file's contents are encoded on their way to the client.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Coding {
+    /// As they are on disk.
+    Identity,
+    /// As they are on disk, which is already compressed: we're sending an
+    /// alternate.
+    Precompressed(ContentEncoding),
+    /// Gzipped as we send them, so we can't know the length in advance.
+    Gzipping,
+}
+
+impl Coding {
+    /// The encoding the client will see.
+    pub fn encoding(self) -> Option<ContentEncoding> {
+        match self {
+            Coding::Identity => None,
+            Coding::Precompressed(e) => Some(e),
+            Coding::Gzipping => Some(ContentEncoding::Gzip),
+        }
+    }
+}
+
+/// What we say about a file, besides its contents.
+pub struct Entity<'a> {
+    pub content_type: &'a [u8],
+    pub coding: Coding,
+    pub etag: EntityTag,
+    /// Whether another client could have been sent another encoding.
+    pub vary: bool,
+    /// What caches should do with the file, if the site has an opinion.
+    pub cache: Option<Policy>,
+    /// Anything else the site wants said.
+    pub headers: Vec<Header>,
+}
+
 pub fn send(
     con: &mut Connection,
-    method: Method,
-    protocol: Protocol,
+    req: &Request,
     now: SystemTime,
-    encoding: Option<ContentEncoding>,
-    if_modified_since: Option<&[u8]>,
-    content_type: &[u8],
+    entity: &Entity,
     resource: OpenFile,
 ) -> Result<()> {
+    let (coding, content_type, etag) =
+        (entity.coding, entity.content_type, &entity.etag);
     let mtime = httpdate::fmt_http_date(resource.mtime);
 
-    let unmodified = if_modified_since == Some(mtime.as_bytes());
+    // If-None-Match, when present, takes precedence over If-Modified-Since.
+    let unmodified = match req.if_none_match {
+        Some(ref condition) => condition.matches_weak(etag),
+        None => req
+            .if_modified_since
+            .is_some_and(|date| HttpDate::from(resource.mtime) <= date),
+    };
 
-    if unmodified {
-        con.log_other(b"note: not modified");
-        start_response(con, protocol, now, b"304", b"not modified")?
-    } else {
-        start_response(con, protocol, now, b"200", b"OK")?
+    // Ranges only apply to GETs that would otherwise succeed, for content we
+    // aren't compressing as we go.  If-Range names the version of the resource
+    // the client has parts of; if we've moved on, it needs the whole thing.
+    let selection = match req.range {
+        Some(ref specs)
+            if req.method == Method::Get
+                && coding != Coding::Gzipping
+                && !unmodified
+                && req
+                    .if_range
+                    .as_deref()
+                    .is_none_or(|v| if_range_matches(v, &mtime, etag)) =>
+        {
+            range::select(specs, resource.length)
+        }
+        _ => Selection::Whole,
+    };
+
+    match selection {
+        Selection::Whole if unmodified => {
+            con.log_other(b"note: not modified");
+            start_response(con, req.protocol, now, b"304", b"not modified")?
+        }
+        Selection::Whole => {
+            start_response(con, req.protocol, now, b"200", b"OK")?
+        }
+        Selection::Partial(_) => {
+            start_response(con, req.protocol, now, b"206", b"partial content")?
+        }
+        Selection::Unsatisfiable => {
+            con.log_other(b"note: range not satisfiable");
+            start_response(
+                con,
+                req.protocol,
+                now,
+                b"416",
+                b"range not satisfiable",
+            )?
+        }
     }
-    con.write(b"Content-Type: ")?;
-    con.write(content_type)?;
-    con.write(b"\r\n")?;
 
     con.write(b"Last-Modified: ")?;
     con.write(mtime.as_bytes())?;
-    con.write(b"\r\n")?;
-
-    if let Some(ContentEncoding::Gzip) = encoding {
-        con.write(b"Content-Encoding: gzip\r\n")?
+    con.write(b"\r\nETag: ")?;
+    con.write(&etag.to_bytes())?;
+    if let Some(ref policy) = entity.cache {
+        write_cache_policy(con, now, policy)?;
+    }
+    for header in &entity.headers {
+        con.write(b"\r\n")?;
+        con.write(&header.name)?;
+        con.write(b": ")?;
+        con.write(&header.value)?;
+    }
+    con.write(if coding == Coding::Gzipping {
+        &b"\r\nAccept-Ranges: none\r\n"[..]
+    } else {
+        b"\r\nAccept-Ranges: bytes\r\n"
+    })?;
+    if entity.vary {
+        con.write(b"Vary: Accept-Encoding\r\n")?;
     }
+    write_connection(con, req)?;
 
-    let send_content = method == Method::Get && !unmodified;
+    if let Some(encoding) = coding.encoding() {
+        con.write(b"Content-Encoding: ")?;
+        con.write(encoding.token())?;
+        con.write(b"\r\n")?;
+    }
 
-    let r = match protocol {
-        Protocol::Http10 => send_unencoded(con, send_content, resource),
-        Protocol::Http11 => send_chunked(con, send_content, resource),
+    let r = match selection {
+        Selection::Whole => {
+            con.write(b"Content-Type: ")?;
+            con.write(content_type)?;
+            con.write(b"\r\n")?;
+
+            // Whenever we can, announce the length up front.  If the file
+            // appears to be changing under us, fall back to chunking so that
+            // we can send whatever is there when we read it -- unless the
+            // client can't handle that, in which case we do our best.
+            let send_content = req.method == Method::Get && !unmodified;
+            if coding == Coding::Gzipping {
+                let chunked = req.protocol == Protocol::Http11;
+                send_gzipping(con, chunked, send_content, resource)
+            } else if req.protocol == Protocol::Http11
+                && !length_is_stable(&resource, now)
+            {
+                con.log_other(b"note: file is changing; chunking");
+                send_chunked(con, send_content, resource)
+            } else {
+                send_unencoded(con, send_content, resource)
+            }
+        }
+        Selection::Partial(ref ranges) if ranges.len() == 1 => {
+            con.write(b"Content-Type: ")?;
+            con.write(content_type)?;
+            con.write(b"\r\n")?;
+            send_range(con, resource, ranges[0])
+        }
+        Selection::Partial(ref ranges) => {
+            send_multipart(con, now, content_type, resource, ranges)
+        }
+        Selection::Unsatisfiable => {
+            con.write(b"Content-Range: bytes */")?;
+            con.write_decimal(resource.length as usize)?;
+            con.write(b"\r\nContent-Length: 0\r\n\r\n")
+        }
     };
 
     con.flush_output()?;
-    r
+    r.and_then(|_| end_of_message(req))
+}
+
+/// Tells caches what to do with the response.
+fn write_cache_policy(
+    con: &mut Connection,
+    now: SystemTime,
+    policy: &Policy,
+) -> Result<()> {
+    con.write(b"\r\nCache-Control: ")?;
+    con.write(&policy.directives)?;
+    if let Some(expires) = policy
+        .max_age
+        .and_then(|age| now.checked_add(Duration::from_secs(age)))
+    {
+        con.write(b"\r\nExpires: ")?;
+        con.write(httpdate::fmt_http_date(expires).as_bytes())?;
+    }
+    Ok(())
+}
+
+/// Checks whether the length we recorded when opening `resource` is likely to
+/// be the length we'll actually read: the file mustn't have been touched
+/// recently, nor since we opened it.
+fn length_is_stable(resource: &OpenFile, now: SystemTime) -> bool {
+    if resource.recently_modified(now) {
+        return false;
+    }
+
+    match resource.file.metadata() {
+        Ok(meta) => {
+            meta.len() == resource.length
+                && meta.modified().ok() == Some(resource.mtime)
+        }
+        Err(_) => false,
+    }
+}
+
+/// Evaluates an `If-Range` validator, which may be either an entity tag or a
+/// date.  Entity tags must match strongly; dates must match our
+/// `Last-Modified` exactly.
+fn if_range_matches(validator: &[u8], mtime: &str, etag: &EntityTag) -> bool {
+    match EntityTag::parse(validator) {
+        Some(ref tag) => tag.strong_eq(etag),
+        None => validator == mtime.as_bytes(),
+    }
 }
 
 /// Signals the given error to the client.
 ///
+/// If `root`, the requested host's directory, has a page for the error in
+/// `:errors` -- named for the status code, like `:errors/404.html` -- we send
+/// that.  Otherwise, the client gets a terse page of our own.
+///
 /// Currently, this also closes the connection, though this seems like a
 /// decision better left to the caller (TODO).
 pub fn barf(
     mut con: Connection,
     protocol: Option<Protocol>,
+    root: Option<&[u8]>,
+    send_content: bool,
+    error: HttpError,
+) -> Result<()> {
+    let result = send_error(&mut con, protocol, root, send_content, error);
+    // Even if there was nothing to send, we may have been interrupted partway
+    // through a response, which is still worth logging.
+    con.log_access();
+    result
+}
+
+fn send_error(
+    con: &mut Connection,
+    protocol: Option<Protocol>,
+    root: Option<&[u8]>,
     send_content: bool,
     error: HttpError,
 ) -> Result<()> {
@@ -70,91 +303,175 @@ pub fn barf(
         None => return Ok(()),
         Some(pair) => pair,
     };
+    let page = root.and_then(|r| error_page(r, code));
 
     start_response(
-        &mut con,
+        con,
         protocol.unwrap_or(Protocol::Http10),
         SystemTime::now(),
         code,
         message,
     )?;
-    con.write(b"Content-Length: ")?;
-    con.write_decimal(message.len() + 28)?; // length of HTML wrapper
-    con.write(b"\r\n")?;
-
     if protocol == Some(Protocol::Http11) {
         con.write(b"Connection: close\r\n")?;
     }
 
-    con.write(b"Content-Type: text/html\r\n\r\n")?;
-
-    if send_content {
-        con.write(b"<html><body>")?;
-        con.write(message)?;
-        con.write(b"</body></html>\r\n")?;
+    match page {
+        Some((content_type, page)) => {
+            con.write(b"Content-Type: ")?;
+            con.write(&content_type)?;
+            con.write(b"\r\n")?;
+            send_unencoded(con, send_content, page)?;
+        }
+        None => {
+            con.write(b"Content-Length: ")?;
+            con.write_decimal(message.len() + 28)?; // length of HTML wrapper
+            con.write(b"\r\nContent-Type: text/html\r\n\r\n")?;
+
+            if send_content {
+                con.write_body(b"<html><body>")?;
+                con.write_body(message)?;
+                con.write_body(b"</body></html>\r\n")?;
+            }
+        }
     }
 
     con.flush_output()
 }
 
-/// Sends a permanent redirect to the client.  The connection stays open.
+/// Looks for a custom page for errors with status `code`, returning it along
+/// with its content type.  Error pages are subject to the same permission
+/// checks as anything else we serve.
+fn error_page(
+    root: &[u8],
+    code: &[u8],
+) -> Option<(Cow<'static, [u8]>, OpenFile)> {
+    let mut path = root.to_vec();
+    path.extend_from_slice(b"/:errors/");
+    path.extend_from_slice(code);
+    path.extend_from_slice(b".html");
+
+    match file::safe_open(ffi::OsStr::from_bytes(&path)) {
+        Ok(FileOrDir::File(page)) => Some((filetype::from_path(&path), page)),
+        _ => None,
+    }
+}
+
+/// The kinds of redirect we send.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Redirect {
+    /// 301: the resource has moved for good.
+    Moved,
+    /// 302: the resource is elsewhere for now.
+    Found,
+    /// 307: like 302, but the client mustn't change the method.
+    Temporary,
+    /// 308: like 301, but the client mustn't change the method.
+    Permanent,
+}
+
+impl Redirect {
+    /// Finds the redirect with the given status code.
+    pub fn from_code(code: &[u8]) -> Option<Redirect> {
+        match code {
+            b"301" => Some(Redirect::Moved),
+            b"302" => Some(Redirect::Found),
+            b"307" => Some(Redirect::Temporary),
+            b"308" => Some(Redirect::Permanent),
+            _ => None,
+        }
+    }
+
+    /// The status code and message we send.
+    fn status(self) -> (&'static [u8], &'static [u8]) {
+        match self {
+            Redirect::Moved => (b"301", b"moved permanently"),
+            Redirect::Found => (b"302", b"found"),
+            Redirect::Temporary => (b"307", b"temporary redirect"),
+            Redirect::Permanent => (b"308", b"permanent redirect"),
+        }
+    }
+}
+
+/// Sends the client to `location`.  The connection stays open if the client
+/// wants it to.
 pub fn redirect(
     con: &mut Connection,
-    protocol: Protocol,
-    send_content: bool,
+    req: &Request,
+    kind: Redirect,
     location: &[u8],
 ) -> Result<()> {
-    let body = b"<html><body>moved permanently</body></html>";
+    let (code, message) = kind.status();
+    let mut body = b"<html><body>".to_vec();
+    body.extend_from_slice(message);
+    body.extend_from_slice(b"</body></html>");
 
     let now = SystemTime::now();
-    start_response(con, protocol, now, b"301", b"moved permanently")?;
+    start_response(con, req.protocol, now, code, message)?;
     con.write(b"Content-Length: ")?;
     con.write_decimal(body.len())?;
     con.write(b"\r\nLocation: ")?;
     con.write(location)?;
     con.write(b"\r\n")?;
+    write_connection(con, req)?;
 
     con.write(b"Content-Type: text/html\r\n\r\n")?;
 
-    if send_content {
-        con.write(body)?;
+    if req.method == Method::Get {
+        con.write_body(&body)?;
     }
 
     con.flush_output()?;
+    end_of_message(req)
+}
 
-    match protocol {
-        Protocol::Http10 => Err(HttpError::ConnectionClosed),
-        Protocol::Http11 => Ok(()),
+/// Sends a body we've generated ourselves, rather than one read from a file.
+/// `vary` names the request headers that went into it, if any.
+pub fn send_generated(
+    con: &mut Connection,
+    req: &Request,
+    content_type: &[u8],
+    vary: Option<&[u8]>,
+    body: &[u8],
+) -> Result<()> {
+    start_response(con, req.protocol, SystemTime::now(), b"200", b"OK")?;
+    con.write(b"Content-Length: ")?;
+    con.write_decimal(body.len())?;
+    con.write(b"\r\n")?;
+    if let Some(vary) = vary {
+        con.write(b"Vary: ")?;
+        con.write(vary)?;
+        con.write(b"\r\n")?;
     }
+    write_connection(con, req)?;
+
+    con.write(b"Content-Type: ")?;
+    con.write(content_type)?;
+    con.write(b"\r\n\r\n")?;
+
+    if req.method == Method::Get {
+        con.write_body(body)?;
+    }
+
+    con.flush_output()?;
+    end_of_message(req)
 }
 
 fn send_unencoded(
     con: &mut Connection,
     send_content: bool,
-    resource: OpenFile,
+    mut resource: OpenFile,
 ) -> Result<()> {
     con.write(b"Content-Length: ")?;
     con.write_decimal(resource.length as usize)?;
     con.write(b"\r\n\r\n")?;
 
     if send_content {
-        let mut input = io::BufReader::with_capacity(1024, resource.file);
-        loop {
-            let count = {
-                let chunk = input.fill_buf()?;
-                if chunk.is_empty() {
-                    break;
-                }
-                con.write(chunk)?;
-                chunk.len()
-            };
-            input.consume(count);
-        }
+        // Send exactly what we promised, even if the file has grown since, so
+        // that a persistent connection stays in sync.
+        copy_body(con, &mut resource.file, 0, resource.length)?;
     }
-
-    // We use unencoded responses for HTTP/1.0 clients, and we assume that
-    // they don't use persistent connections.  This merits reconsideration (TODO).
-    Err(HttpError::ConnectionClosed)
+    Ok(())
 }
 
 fn send_chunked(
@@ -171,7 +488,7 @@ fn send_chunked(
                 let chunk = input.fill_buf()?;
                 con.write_hex(chunk.len())?;
                 con.write(b"\r\n")?;
-                con.write(chunk)?;
+                con.write_body(chunk)?;
                 con.write(b"\r\n")?;
 
                 chunk.len()
@@ -184,11 +501,207 @@ fn send_chunked(
             input.consume(count)
         }
     }
+    Ok(())
+}
+
+/// Sends `resource` gzipped as we go.  We can't announce the length, so the
+/// body is chunked if `chunked`; otherwise, the protocol had better have
+/// another way of marking the end.
+fn send_gzipping(
+    con: &mut Connection,
+    chunked: bool,
+    send_content: bool,
+    mut resource: OpenFile,
+) -> Result<()> {
+    if chunked {
+        con.write(b"Transfer-Encoding: chunked\r\n")?;
+    }
+    con.write(b"\r\n")?;
+
+    if send_content {
+        resource.file.seek(io::SeekFrom::Start(0))?;
+        let input = (&mut resource.file).take(resource.length);
+        let mut body = BodyWriter {
+            con: &mut *con,
+            chunked,
+            error: None,
+        };
+        let result = compress::gzip(input, &mut body);
+        if let Some(e) = body.error {
+            return Err(e);
+        }
+        result?;
 
-    // Leave the connection open for more requests.
+        if chunked {
+            con.write(b"0\r\n\r\n")?;
+        }
+    }
     Ok(())
 }
 
+/// Adapts a `Connection` to `io::Write`, for the compressor's benefit, as
+/// the body of a response.
+struct BodyWriter<'a> {
+    con: &'a mut Connection,
+    chunked: bool,
+    /// The first error the connection reported, which is more useful to us
+    /// than the `io::Error` we have to return instead.
+    error: Option<HttpError>,
+}
+
+impl io::Write for BodyWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // An empty chunk would end the body.
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let chunked = self.chunked;
+        let con = &mut *self.con;
+        let result = (|| {
+            if chunked {
+                con.write_hex(buf.len())?;
+                con.write(b"\r\n")?;
+            }
+            con.write_body(buf)?;
+            if chunked {
+                con.write(b"\r\n")?;
+            }
+            Ok(())
+        })();
+
+        match result {
+            Ok(()) => Ok(buf.len()),
+            Err(e) => {
+                self.error.get_or_insert(e);
+                Err(io::Error::new(io::ErrorKind::BrokenPipe, "write failed"))
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Sends a single range of `resource` as the body of a 206 response.
+fn send_range(
+    con: &mut Connection,
+    mut resource: OpenFile,
+    range: ByteRange,
+) -> Result<()> {
+    write_content_range(con, range, resource.length)?;
+    con.write(b"Content-Length: ")?;
+    con.write_decimal(range.len() as usize)?;
+    con.write(b"\r\n\r\n")?;
+
+    copy_body(con, &mut resource.file, range.first, range.len())
+}
+
+/// Sends several ranges of `resource` as a `multipart/byteranges` body.
+fn send_multipart(
+    con: &mut Connection,
+    now: SystemTime,
+    content_type: &[u8],
+    mut resource: OpenFile,
+    ranges: &[ByteRange],
+) -> Result<()> {
+    // The boundary must not appear in the content.  We can't cheaply promise
+    // that, but making it hard to predict keeps it from being contrived.
+    let boundary = format!(
+        "screaming{:x}{:x}",
+        now.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos()),
+        resource.length,
+    );
+
+    // We know exactly what we're going to send, so work out the part headers
+    // up front to determine the Content-Length.
+    let part_headers: Vec<Vec<u8>> = ranges
+        .iter()
+        .map(|r| {
+            let mut h = Vec::new();
+            h.extend_from_slice(b"\r\n--");
+            h.extend_from_slice(boundary.as_bytes());
+            h.extend_from_slice(b"\r\nContent-Type: ");
+            h.extend_from_slice(content_type);
+            h.extend_from_slice(
+                format!(
+                    "\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+                    r.first, r.last, resource.length
+                )
+                .as_bytes(),
+            );
+            h
+        })
+        .collect();
+    let trailer = format!("\r\n--{}--\r\n", boundary);
+
+    let length = part_headers.iter().map(|h| h.len() as u64).sum::<u64>()
+        + ranges.iter().map(ByteRange::len).sum::<u64>()
+        + trailer.len() as u64;
+
+    con.write(b"Content-Type: multipart/byteranges; boundary=")?;
+    con.write(boundary.as_bytes())?;
+    con.write(b"\r\nContent-Length: ")?;
+    con.write_decimal(length as usize)?;
+    con.write(b"\r\n\r\n")?;
+
+    for (header, &range) in part_headers.iter().zip(ranges) {
+        con.write_body(header)?;
+        copy_body(con, &mut resource.file, range.first, range.len())?;
+    }
+    con.write_body(trailer.as_bytes())
+}
+
+fn write_content_range(
+    con: &mut Connection,
+    range: ByteRange,
+    length: u64,
+) -> Result<()> {
+    con.write(b"Content-Range: bytes ")?;
+    con.write_decimal(range.first as usize)?;
+    con.write(b"-")?;
+    con.write_decimal(range.last as usize)?;
+    con.write(b"/")?;
+    con.write_decimal(length as usize)?;
+    con.write(b"\r\n")
+}
+
+/// Copies `length` bytes starting at `offset` from `file` to the client.
+/// We've already promised the client a length, so if the file has shrunk out
+/// from under us, the only honest thing to do is drop the connection.
+fn copy_body(
+    con: &mut Connection,
+    file: &mut fs::File,
+    offset: u64,
+    length: u64,
+) -> Result<()> {
+    if con.write_file(file, offset, length)? < length {
+        Err(HttpError::ConnectionClosed)
+    } else {
+        Ok(())
+    }
+}
+
+/// Tells the client what will become of the connection after this response,
+/// if that differs from the protocol's default.
+fn write_connection(con: &mut Connection, req: &Request) -> Result<()> {
+    match (req.protocol, req.keep_alive) {
+        (Protocol::Http10, true) => con.write(b"Connection: keep-alive\r\n"),
+        (Protocol::Http11, false) => con.write(b"Connection: close\r\n"),
+        _ => Ok(()),
+    }
+}
+
+/// Decides the fate of the connection after a complete response.
+fn end_of_message(req: &Request) -> Result<()> {
+    if req.keep_alive {
+        Ok(())
+    } else {
+        Err(HttpError::ConnectionClosed)
+    }
+}
+
 /// Begins a response, printing the status line and a set of common headers.
 /// The caller should follow up by adding any desired headers and then writing
 /// a CRLF.
@@ -204,12 +717,54 @@ fn start_response(
     con.write(match prot {
         Protocol::Http10 => b"HTTP/1.0 ",
         Protocol::Http11 => b"HTTP/1.1 ",
+        Protocol::Http2 => b"HTTP/2 ",
     })?;
     con.write(code)?;
     con.write(b" ")?;
     con.write(msg)?;
+    con.record().status = Some(code.to_vec());
     con.write(b"\r\nServer: abstract screaming\r\nDate: ")?;
     con.write(now.as_bytes())?;
     con.write(b"\r\n")?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file::TempDir;
+
+    #[test]
+    fn test_barf_error_page() {
+        let dir = TempDir::new("errors");
+        fs::create_dir(dir.path().join(":errors")).unwrap();
+        fs::write(
+            dir.path().join(":errors/404.html"),
+            b"<p>no such page</p>\n",
+        )
+        .unwrap();
+        let root = dir.as_bytes().to_vec();
+
+        // A host with a page for the error gets it.
+        let (c, out, _) = Connection::in_memory(b"");
+        barf(
+            c,
+            Some(Protocol::Http11),
+            Some(&root),
+            true,
+            HttpError::NotFound(b"file does not exist"),
+        )
+        .unwrap();
+        let out = out.contents();
+        assert!(out.starts_with(b"HTTP/1.1 404 not found\r\n"));
+        assert!(out.windows(20).any(|w| w == b"Content-Length: 20\r\n"));
+        assert!(out.ends_with(b"\r\n\r\n<p>no such page</p>\n"));
+
+        // Other errors fall back to the builtin page.
+        let (c, out, _) = Connection::in_memory(b"");
+        barf(c, None, Some(&root), true, HttpError::BadRequest).unwrap();
+        assert!(out
+            .contents()
+            .ends_with(b"\r\n\r\n<html><body>bad request</body></html>\r\n"));
+    }
+}
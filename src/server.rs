@@ -1,116 +1,517 @@
 //! The core HTTP server, which ties the other modules together.
 
+use std::cmp::Reverse;
 use std::ffi;
+use std::fs;
+use std::net::{IpAddr, Ipv6Addr};
 use std::os::unix::ffi::OsStrExt;
 use std::time::SystemTime;
 
+use httpdate::HttpDate;
+
 use crate::con::Connection;
 use crate::error::*;
-use crate::file::{self, FileOrDir};
+use crate::etag::EntityTag;
+use crate::file::{self, FileOrDir, OpenFile};
+use crate::redirects::Outcome;
 use crate::request::{Method, Protocol, Request};
-use crate::response::ContentEncoding;
-use crate::{filetype, path, percent, request, response};
-
-pub fn serve(remote: String) -> Result<()> {
-    let mut c = Connection::new(remote);
+use crate::response::{Coding, ContentEncoding, Entity, Redirect};
+use crate::{
+    aliases, autoindex, cache_control, compress, filetype, headers, http2,
+    path, percent, redirects, request, response,
+};
 
+/// Serves requests on `c` until the client goes away or an error ends the
+/// connection.
+pub fn serve(mut c: Connection) -> Result<()> {
     loop {
         // Process requests.
         let req = match request::read(&mut c) {
             Ok(r) => r,
-            Err(e) => return response::barf(c, None, true, e),
+            Err(HttpError::Http2Preface) => return http2::serve(c),
+            Err(e) => return response::barf(c, None, None, true, e),
         };
 
-        // Back up two pieces before we consume the request.
+        // Back up a few pieces before we consume the request.
         let protocol = req.protocol;
         let method = req.method;
+        let root = host_root(&mut c, &req, b".");
 
-        if let Err(error) = serve_request(&mut c, req) {
+        let result = match root {
+            Some(ref root) => serve_request(&mut c, req, root),
+            None => Err(HttpError::BadRequest),
+        };
+        if let Err(error) = result {
             // Try to report this to the client.  Error reporting is best-effort.
-            let _ =
-                response::barf(c, Some(protocol), method == Method::Get, error);
+            let _ = response::barf(
+                c,
+                Some(protocol),
+                root.as_deref(),
+                method == Method::Get,
+                error,
+            );
             return Ok(());
         }
 
         // Otherwise, carry on accepting requests.
+        c.log_access();
     }
 }
 
-fn serve_request(con: &mut Connection, req: Request) -> Result<()> {
+/// Serves the single request waiting on `c`, which is an HTTP/2 stream
+/// translated into HTTP/1.1.  Errors are reported to the client as usual,
+/// except `ConnectionClosed`, which means the response was cut short and the
+/// stream must be abandoned.
+pub fn serve_http2_stream(mut c: Connection) -> Result<()> {
+    let mut req = match request::read(&mut c) {
+        Ok(r) => r,
+        Err(e) => {
+            return response::barf(c, Some(Protocol::Http2), None, true, e)
+        }
+    };
+    // Whether streams continue is none of the request's business.
+    req.protocol = Protocol::Http2;
+    req.keep_alive = true;
+    // Log the request as the client made it, not as we translated it.
+    let line = &mut c.record().request_line;
+    if line.ends_with(b" HTTP/1.1") {
+        line.truncate(line.len() - 8);
+        line.extend_from_slice(b"HTTP/2.0");
+    }
+
+    let method = req.method;
+    let root = host_root(&mut c, &req, b".");
+    let result = match root {
+        Some(ref root) => serve_request(&mut c, req, root),
+        None => Err(HttpError::BadRequest),
+    };
+    match result {
+        Err(HttpError::ConnectionClosed) => {
+            c.log_access();
+            Err(HttpError::ConnectionClosed)
+        }
+        Err(e) => response::barf(
+            c,
+            Some(Protocol::Http2),
+            root.as_deref(),
+            method == Method::Get,
+            e,
+        ),
+        Ok(()) => {
+            c.log_access();
+            Ok(())
+        }
+    }
+}
+
+/// Works out the directory holding the requested host's files, in the
+/// document root `docroot`, or returns `None` if the request doesn't name a
+/// host we could serve.
+fn host_root(
+    con: &mut Connection,
+    req: &Request,
+    docroot: &[u8],
+) -> Option<Vec<u8>> {
     // The request may not have included a Host, but we need to use it to
     // generate a file path.  Tolerate Host's absence for HTTP/1.0 requests
     // by replacing it with the simulated host "0".
     let host = match (&req.host, req.protocol) {
-        (Some(h), _) => h.as_slice(),
-        (None, Protocol::Http10) => b"0",
+        (Some(h), _) => normalize_host(h)?,
+        (None, Protocol::Http10) => b"0".to_vec(),
         // HTTP 1.1 requests must include a host, one way or another.
-        _ => return Err(HttpError::BadRequest),
+        _ => return None,
     };
 
-    let mut file_path = Vec::with_capacity(2 + host.len() + 1 + req.path.len());
-    file_path.extend_from_slice(b"./");
-    normalize_host(host, &mut file_path);
+    let config = con.config();
+    let default = config.default_host.as_deref();
+    let name = resolve_host(&DocRoot(docroot), &host, default, |note| {
+        con.log_other(note)
+    });
+    let mut root = docroot.to_vec();
+    root.push(b'/');
+    root.extend_from_slice(&name);
+    con.record().vhost = Some(name);
+    Some(root)
+}
+
+/// What `resolve_host` needs to know about the virtual hosts in a document
+/// root.
+pub trait Hosts {
+    /// Finds the host that `host` is an alias of, if any.
+    fn alias_of(&self, host: &[u8]) -> Option<Vec<u8>>;
+    /// Checks whether `host` has a directory of its own.
+    fn has_dir(&self, host: &[u8]) -> bool;
+}
+
+/// The document root at the path it holds, consulted as need be.
+pub struct DocRoot<'a>(pub &'a [u8]);
+
+impl Hosts for DocRoot<'_> {
+    fn alias_of(&self, host: &[u8]) -> Option<Vec<u8>> {
+        aliases::lookup(self.0, host)
+    }
+
+    fn has_dir(&self, host: &[u8]) -> bool {
+        let mut dir = self.0.to_vec();
+        dir.push(b'/');
+        dir.extend_from_slice(host);
+        fs::metadata(ffi::OsStr::from_bytes(&dir))
+            .is_ok_and(|meta| meta.is_dir())
+    }
+}
+
+/// Works out which of `hosts` serves `host`, already normalized, and returns
+/// the name of its directory.  Aliases are followed, and a host that still
+/// has no directory of its own gets `default`'s, if there is one.  Each of
+/// those is described to `note` as it happens.
+pub fn resolve_host(
+    hosts: &impl Hosts,
+    host: &[u8],
+    default: Option<&[u8]>,
+    mut note: impl FnMut(&[u8]),
+) -> Vec<u8> {
+    let mut name = match hosts.alias_of(host) {
+        Some(target) => {
+            let mut msg = b"note: host ".to_vec();
+            msg.extend_from_slice(host);
+            msg.extend_from_slice(b" is an alias of ");
+            msg.extend_from_slice(&target);
+            note(&msg);
+            target
+        }
+        None => host.to_vec(),
+    };
+
+    match default {
+        Some(default) if !hosts.has_dir(&name) => {
+            let mut msg = b"note: unknown host ".to_vec();
+            msg.extend_from_slice(&name);
+            msg.extend_from_slice(b"; using ");
+            msg.extend_from_slice(default);
+            note(&msg);
+            name = default.to_vec();
+        }
+        _ => (),
+    }
+    name
+}
+
+fn serve_request(
+    con: &mut Connection,
+    req: Request,
+    root: &[u8],
+) -> Result<()> {
+    let mut file_path = root.to_vec();
     file_path.push(b'/');
     percent::unescape(&req.path, &mut file_path)?;
     path::sanitize(&mut file_path);
 
+    let query = req.query.as_deref();
+    match redirects::lookup(con, root, &file_path[root.len()..], query) {
+        Some(Outcome::Redirect(kind, location)) => {
+            con.log(&file_path, None, b"redirect");
+            let url = if location.starts_with(b"/") {
+                absolute_url(con, &req, &location)
+            } else {
+                location
+            };
+            return response::redirect(con, &req, kind, &url);
+        }
+        Some(Outcome::Rewrite(new_path)) => {
+            con.log(&file_path, None, b"rewrite");
+            file_path.truncate(root.len());
+            file_path.extend_from_slice(&new_path);
+            path::sanitize(&mut file_path);
+        }
+        None => (),
+    }
+
+    // A path that, from simple textual inspection, names a directory gets the
+    // first of the directory's index pages that exists -- or, failing that, a
+    // listing if we're allowed to make one.
+    if file_path.ends_with(b"/") {
+        let config = con.config();
+        let dir_len = file_path.len();
+        let mut misses = Vec::new();
+        for name in &config.index_names {
+            file_path.truncate(dir_len);
+            file_path.extend_from_slice(name);
+            match file::safe_open(ffi::OsStr::from_bytes(&file_path)) {
+                Ok(FileOrDir::File(resource)) => {
+                    con.log(&file_path, Some(b"index"), b"success");
+                    return serve_file(con, &req, root, file_path, resource);
+                }
+                // An index that's a directory is no index at all.
+                miss @ (Ok(FileOrDir::Dir) | Err(HttpError::NotFound(_))) => {
+                    misses.push((file_path.clone(), miss))
+                }
+                Err(e) => {
+                    if let Some(message) = e.log_message() {
+                        con.log(&file_path, Some(b"index"), &message);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        // The candidates we passed over only matter if none of them was there.
+        for (path, miss) in misses {
+            log_open(con, &path, Some(b"index"), &miss);
+        }
+
+        return if config.autoindex {
+            autoindex::send(con, &req, &file_path[..dir_len])
+        } else {
+            Err(HttpError::NotFound(b"no index"))
+        };
+    }
+
+    match open_resource(con, &file_path, None)? {
+        FileOrDir::File(resource) => {
+            serve_file(con, &req, root, file_path, resource)
+        }
+        FileOrDir::Dir => {
+            // Nothing from the request goes into the URL unescaped.
+            let mut path = Vec::new();
+            percent::unescape(&req.path, &mut path)?;
+            let mut location = Vec::new();
+            percent::escape(&path, &mut location);
+            location.push(b'/');
+            if let Some(ref query) = req.query {
+                location.push(b'?');
+                percent::escape_query(query, &mut location);
+            }
+            let url = absolute_url(con, &req, &location);
+            response::redirect(con, &req, Redirect::Moved, &url)
+        }
+    }
+}
+
+/// Sends the file at `file_path`, already opened as `resource`, or a
+/// compressed alternate if there's a suitable one.  `root` is the host's
+/// directory, which `file_path` is in.
+fn serve_file(
+    con: &mut Connection,
+    req: &Request,
+    root: &[u8],
+    mut file_path: Vec<u8>,
+    mut resource: OpenFile,
+) -> Result<()> {
     let now = SystemTime::now();
     let content_type = filetype::from_path(&file_path);
-    if let FileOrDir::File(mut resource) = open_resource(con, &file_path, None)?
+    let cache = cache_control::lookup(root, &file_path[root.len()..]);
+    let headers = headers::lookup(root, &file_path[root.len()..]);
+    let mut coding = Coding::Identity;
+
+    // See if there's *also* a precompressed alternate with accessible
+    // permissions, in an encoding the client accepts.  Try them in the
+    // client's order of preference, skipping any it likes less than the file
+    // as-is.
+    let accept = &req.accept_encoding;
+    let identity = accept.identity_weight();
+    let acceptable = |e: &ContentEncoding| {
+        let weight = accept.weight(e.token());
+        weight > 0 && weight >= identity
+    };
+    let mut candidates: Vec<_> = ContentEncoding::ALL
+        .iter()
+        .cloned()
+        .filter(acceptable)
+        .collect();
+    // The sort is stable, so ties keep our own order of preference.
+    candidates.sort_by_key(|e| Reverse(accept.weight(e.token())));
+
+    let primary_len = file_path.len();
+    for candidate in candidates {
+        file_path.truncate(primary_len);
+        file_path.push(b'.');
+        file_path.extend_from_slice(candidate.extension());
+        if let Ok(FileOrDir::File(alt)) =
+            open_resource(con, &file_path, Some(candidate.token()))
+        {
+            // It must be at least as recent as the primary, or we'll assume it's
+            // stale clutter and ignore it.
+            if alt.mtime >= resource.mtime {
+                // Rewrite the file and length, but leave everything else
+                // (particularly mtime).
+                let mut note = b"note: serving ".to_vec();
+                note.extend_from_slice(candidate.token());
+                con.log_other(&note);
+                resource.file = alt.file;
+                resource.length = alt.length;
+                coding = Coding::Precompressed(candidate);
+                break;
+            }
+        }
+    }
+
+    // Failing that, we may be able to compress it ourselves.  Whether it comes
+    // from the cache or is made as we send it, our copy is tagged after the
+    // file it came from -- and weakly, as we don't promise to compress the
+    // same way every time.
+    let compressible =
+        con.config().compress && filetype::is_compressible(&content_type);
+    file_path.truncate(primary_len);
+    let mut etag = None;
+    if coding == Coding::Identity
+        && compressible
+        && acceptable(&ContentEncoding::Gzip)
     {
-        let mut encoding = None;
-
-        // If that worked, see if there's *also* a GZIPped alternate with accessible
-        // permissions.
-        if req.accept_gzip {
-            file_path.extend_from_slice(b".gz");
-            if let Ok(FileOrDir::File(alt)) =
-                open_resource(con, &file_path, Some(b"gzipped"))
-            {
-                // It must be at least as recent as the primary, or we'll assume it's
-                // stale clutter and ignore it.
-                if alt.mtime >= resource.mtime {
-                    // Rewrite the file and length, but leave everything else
-                    // (particularly mtime).
-                    con.log_other(b"note: serving gzipped");
-                    resource.file = alt.file;
-                    resource.length = alt.length;
-                    encoding = Some(ContentEncoding::Gzip)
+        let mut tag =
+            EntityTag::for_file(&resource, Some(ContentEncoding::Gzip), now);
+        tag.weak = true;
+        coding = gzip_resource(con, req, &file_path, &mut resource, now);
+        if coding != Coding::Identity {
+            etag = Some(tag);
+        }
+    }
+
+    // Whatever this client accepts, caches have to know that others might
+    // get something else.
+    let vary = coding != Coding::Identity
+        || compressible
+        || ContentEncoding::ALL
+            .iter()
+            .any(|&e| has_alternate(&file_path, e, resource.mtime));
+
+    let etag = etag.unwrap_or_else(|| {
+        EntityTag::for_file(&resource, coding.encoding(), now)
+    });
+    check_preconditions(req, &resource, &etag)?;
+
+    let entity = Entity {
+        content_type: &content_type,
+        coding,
+        etag,
+        vary,
+        cache,
+        headers,
+    };
+    response::send(con, req, now, &entity, resource)
+}
+
+/// Checks whether the file at `file_path` has an alternate in `encoding` that
+/// we'd serve to a client that accepted it: one at least as recent as `mtime`
+/// that passes the usual checks.  Nothing is logged, since we aren't sending
+/// it.
+fn has_alternate(
+    file_path: &[u8],
+    encoding: ContentEncoding,
+    mtime: SystemTime,
+) -> bool {
+    let mut alt = file_path.to_vec();
+    alt.push(b'.');
+    alt.extend_from_slice(encoding.extension());
+    fs::metadata(ffi::OsStr::from_bytes(&alt)).is_ok_and(|meta| {
+        meta.is_file()
+            && file::check_mode(&meta).is_ok()
+            && meta.modified().is_ok_and(|t| t >= mtime)
+    })
+}
+
+/// Arranges for the file at `file_path`, opened as `resource`, to be sent
+/// gzipped: from the cache, if there is one, compressing it into the cache if
+/// need be -- or else as we send it.
+fn gzip_resource(
+    con: &mut Connection,
+    req: &Request,
+    file_path: &[u8],
+    resource: &mut OpenFile,
+    now: SystemTime,
+) -> Coding {
+    let config = con.config();
+    if let Some(ref dir) = config.compress_cache {
+        let cached = compress::cache_path(dir, file_path, resource);
+        let mut found = open_resource(con, &cached, Some(b"cached"));
+        // A file that may still be changing isn't worth keeping a copy of.
+        if found.is_err()
+            && !resource.recently_modified(now)
+            && resource.length <= compress::CACHE_LIMIT
+        {
+            match compress::fill_cache(&cached, resource) {
+                Ok(()) => found = open_resource(con, &cached, Some(b"cached")),
+                Err(e) => {
+                    let mut note = b"note: cannot cache: ".to_vec();
+                    note.extend_from_slice(e.to_string().as_bytes());
+                    con.log_other(&note);
                 }
             }
         }
 
-        response::send(
-            con,
-            req.method,
-            req.protocol,
-            now,
-            encoding,
-            req.if_modified_since.as_ref().map(Vec::as_slice),
-            &content_type,
-            resource,
-        )
-    } else {
-        // It's a dir.
-        if let Some(ref orig_host) = req.host {
-            let url: Vec<_> = b"http://"
-                .iter()
-                .chain(orig_host)
-                .chain(&req.path)
-                .chain(b"/".iter())
-                .cloned()
-                .collect();
-
-            return response::redirect(
-                con,
-                req.protocol,
-                req.method == Method::Get,
-                &url,
-            );
-        } else {
-            Err(HttpError::NotFound(b"cannot redirect"))
+        if let Ok(FileOrDir::File(copy)) = found {
+            con.log_other(b"note: serving cached gzip");
+            resource.file = copy.file;
+            resource.length = copy.length;
+            return Coding::Precompressed(ContentEncoding::Gzip);
         }
     }
+
+    // Without a length, an HTTP/1.0 client would only find the end of the
+    // body when we hung up.  That's not worth it.
+    if req.protocol == Protocol::Http10 {
+        return Coding::Identity;
+    }
+    con.log_other(b"note: gzipping");
+    Coding::Gzipping
+}
+
+/// Makes a URL for `path` on the host the client asked for -- port and all --
+/// to redirect it to.  Without a host, the best we can do is a relative URL,
+/// which will have to do.
+fn absolute_url(con: &Connection, req: &Request, path: &[u8]) -> Vec<u8> {
+    match req.host {
+        Some(ref orig_host) => scheme(con, req)
+            .iter()
+            .chain(b"://")
+            .chain(orig_host)
+            .chain(path)
+            .cloned()
+            .collect(),
+        None => path.to_vec(),
+    }
+}
+
+/// Works out the scheme the client used to reach us.  Behind a proxy that
+/// terminates TLS, that's not our own, so we take the word of `PROTO`, or of
+/// `X-Forwarded-Proto` if the proxy is listed in `TRUSTED_PROXIES`.
+fn scheme(con: &Connection, req: &Request) -> &'static [u8] {
+    if con.is_secure() {
+        return b"https";
+    }
+    let config = con.config();
+    let trusted = con
+        .remote()
+        .parse::<IpAddr>()
+        .is_ok_and(|ip| config.trusted_proxies.contains(&ip));
+    let secure = match req.forwarded_tls {
+        Some(secure) if trusted => secure,
+        _ => config.https,
+    };
+    if secure {
+        b"https"
+    } else {
+        b"http"
+    }
+}
+
+/// Evaluates the preconditions that can cause a request to fail outright:
+/// `If-Match`, or `If-Unmodified-Since` in its absence.  (The ones that merely
+/// cut a response short are handled in `response::send`.)
+fn check_preconditions(
+    req: &Request,
+    resource: &OpenFile,
+    etag: &EntityTag,
+) -> Result<()> {
+    let satisfied = match (&req.if_match, req.if_unmodified_since) {
+        (Some(condition), _) => condition.matches_strong(etag),
+        (None, Some(date)) => HttpDate::from(resource.mtime) <= date,
+        (None, None) => true,
+    };
+
+    if satisfied {
+        Ok(())
+    } else {
+        Err(HttpError::PreconditionFailed)
+    }
 }
 
 fn open_resource(
@@ -119,7 +520,17 @@ fn open_resource(
     context: Option<&'static [u8]>,
 ) -> Result<FileOrDir> {
     let result = file::safe_open(ffi::OsStr::from_bytes(path));
+    log_open(con, path, context, &result);
+    result
+}
 
+/// Logs what came of opening the file at `path`.
+fn log_open(
+    con: &mut Connection,
+    path: &[u8],
+    context: Option<&'static [u8]>,
+    result: &Result<FileOrDir>,
+) {
     match result {
         Ok(FileOrDir::File(_)) => {
             con.log(path, context, b"success");
@@ -129,24 +540,503 @@ fn open_resource(
             con.log(path, context, b"directory redirect");
         }
 
-        Err(ref e) => {
+        Err(e) => {
             if let Some(message) = e.log_message() {
-                con.log(path, context, message);
+                con.log(path, context, &message);
             }
         }
     }
+}
 
-    result
+/// If the client provided a host, we must normalize it for use as a directory
+/// name: downcase it, strip off the port, if any, and the trailing dot of a
+/// fully qualified name.  IPv6 addresses keep their brackets, but are
+/// rewritten in their canonical form (RFC 5952), so that each address gets
+/// exactly one directory.
+///
+/// Returns `None` if `orig` isn't a host name, IPv4 address or bracketed IPv6
+/// address, with or without a port (RFC 3986 section 3.2.2).  We're stricter
+/// than the RFC about names: they're dot-separated labels of letters, digits,
+/// hyphens and underscores, which keeps anything that could be mistaken for a
+/// path out of the directory name.  Internationalized names have to arrive in
+/// their ASCII form, which they always do.
+pub fn normalize_host(orig: &[u8]) -> Option<Vec<u8>> {
+    let (host, port) = if orig.starts_with(b"[") {
+        orig.split_at(orig.iter().position(|&b| b == b']')? + 1)
+    } else {
+        orig.split_at(
+            orig.iter().position(|&b| b == b':').unwrap_or(orig.len()),
+        )
+    };
+    match port.split_first() {
+        None => (),
+        Some((b':', digits)) if digits.iter().all(u8::is_ascii_digit) => (),
+        _ => return None,
+    }
+
+    if let Some(literal) = host.strip_prefix(b"[") {
+        let literal =
+            std::str::from_utf8(&literal[..literal.len() - 1]).ok()?;
+        let address: Ipv6Addr = literal.parse().ok()?;
+        return Some(format!("[{}]", address).into_bytes());
+    }
+
+    let name = host.strip_suffix(b".").unwrap_or(host);
+    let valid = !name.is_empty()
+        && name.split(|&b| b == b'.').all(|label| {
+            !label.is_empty()
+                && label.iter().all(|&b| {
+                    b.is_ascii_alphanumeric() || b == b'-' || b == b'_'
+                })
+        });
+    if valid {
+        Some(name.to_ascii_lowercase())
+    } else {
+        None
+    }
 }
 
-// If the client provided a host, we must normalize it for use as a directory
-// name: downcase it and strip off the port, if any.
-fn normalize_host(orig: &[u8], out: &mut Vec<u8>) {
-    for c in orig {
-        if *c == b':' {
-            return;
-        } else {
-            out.push(c.to_ascii_lowercase());
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::con::SharedBuffer;
+    use crate::config::Config;
+    use crate::file::TempDir;
+    use crate::timeout::Timeouts;
+    use std::fs;
+    use std::io;
+    use std::rc::Rc;
+
+    /// Feeds `input` to the server, returning what it sent back.  The tests
+    /// run from the top of the source tree, so the `src` directory stands in
+    /// for a virtual host.
+    fn exchange(input: &[u8]) -> Vec<u8> {
+        let (c, output, _) = Connection::in_memory(input);
+        serve(c).unwrap();
+        output.contents()
+    }
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack.windows(needle.len()).any(|w| w == needle)
+    }
+
+    #[test]
+    fn test_serve_file() {
+        let out = exchange(b"GET /main.rs HTTP/1.0\r\nHost: src\r\n\r\n");
+        let body = fs::read("src/main.rs").unwrap();
+
+        assert!(out.starts_with(b"HTTP/1.0 200 OK\r\n"));
+        let length = format!("Content-Length: {}\r\n", body.len());
+        assert!(contains(&out, length.as_bytes()));
+        assert!(out.ends_with(&body));
+    }
+
+    #[test]
+    fn test_serve_persistent() {
+        let out = exchange(
+            b"HEAD /main.rs HTTP/1.1\r\nHost: src\r\n\r\n\
+              GET /missing HTTP/1.1\r\nHost: SRC:80\r\n\r\n\
+              GET /main.rs HTTP/1.1\r\nHost: src\r\n\r\n",
+        );
+
+        // The 404 ends the connection, so the third request goes unanswered.
+        assert!(out.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        assert!(contains(&out, b"\r\n\r\nHTTP/1.1 404 "));
+        assert_eq!(out.windows(5).filter(|w| w == b"HTTP/").count(), 2);
+    }
+
+    #[test]
+    fn test_serve_file_vary() {
+        let dir = TempDir::new("vary");
+        for name in &["alt.txt", "alt.txt.br", "plain.txt"] {
+            fs::write(dir.path().join(name), b"hello").unwrap();
+        }
+
+        // Serves a file from `dir` to a client that didn't send
+        // Accept-Encoding.
+        let serve = |name: &str, compress: bool| {
+            let (mut c, output, _) =
+                Connection::in_memory(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n");
+            c.set_config(Rc::new(Config {
+                compress,
+                ..Config::default()
+            }));
+            let req = request::read(&mut c).unwrap();
+            let mut file_path = dir.as_bytes().to_vec();
+            file_path.push(b'/');
+            file_path.extend_from_slice(name.as_bytes());
+            let resource = match open_resource(&mut c, &file_path, None) {
+                Ok(FileOrDir::File(f)) => f,
+                _ => panic!("can't open {}", name),
+            };
+            serve_file(&mut c, &req, dir.as_bytes(), file_path, resource)
+                .unwrap();
+            output.contents()
+        };
+
+        let out = serve("alt.txt", false);
+        assert!(contains(&out, b"Vary: Accept-Encoding\r\n"));
+        assert!(!contains(&out, b"Content-Encoding"));
+        let out = serve("plain.txt", true);
+        assert!(contains(&out, b"Vary: Accept-Encoding\r\n"));
+        assert!(!contains(&out, b"Content-Encoding"));
+        let out = serve("plain.txt", false);
+        assert!(!contains(&out, b"Vary"));
+    }
+
+    #[test]
+    fn test_serve_gzip_etag() {
+        let dir = TempDir::new("gzip-etag");
+        let file = dir.path().join("a.txt");
+        fs::write(&file, "hello, hello, hello\n".repeat(100)).unwrap();
+        let hour_ago = SystemTime::now() - std::time::Duration::from_secs(3600);
+        fs::File::options()
+            .write(true)
+            .open(&file)
+            .unwrap()
+            .set_modified(hour_ago)
+            .unwrap();
+        let mut cache = dir.as_bytes().to_vec();
+        cache.extend_from_slice(b"/cache");
+
+        // Gzips a.txt for a client that accepts it, and returns the ETag.
+        let etag = |compress_cache: Option<Vec<u8>>| {
+            let (mut c, output, _) = Connection::in_memory(
+                b"GET / HTTP/1.1\r\nHost: x\r\nAccept-Encoding: gzip\r\n\r\n",
+            );
+            c.set_config(Rc::new(Config {
+                compress: true,
+                compress_cache,
+                ..Config::default()
+            }));
+            let req = request::read(&mut c).unwrap();
+            let mut file_path = dir.as_bytes().to_vec();
+            file_path.extend_from_slice(b"/a.txt");
+            let resource = match open_resource(&mut c, &file_path, None) {
+                Ok(FileOrDir::File(f)) => f,
+                _ => panic!("can't open a.txt"),
+            };
+            serve_file(&mut c, &req, dir.as_bytes(), file_path, resource)
+                .unwrap();
+            let out = output.contents();
+            assert!(contains(&out, b"Content-Encoding: gzip\r\n"));
+            out.split(|&b| b == b'\n')
+                .find(|line| line.starts_with(b"ETag: "))
+                .unwrap()
+                .to_vec()
+        };
+
+        let on_the_fly = etag(None);
+        assert!(on_the_fly.starts_with(b"ETag: W/\""));
+        assert!(on_the_fly.ends_with(b"-gz\"\r"));
+        // Once when filling the cache, and again when serving from it.
+        assert_eq!(etag(Some(cache.clone())), on_the_fly);
+        assert_eq!(etag(Some(cache)), on_the_fly);
+    }
+
+    #[test]
+    fn test_serve_index() {
+        let dir = TempDir::new("index");
+        fs::write(dir.path().join("index.txt"), b"hello").unwrap();
+
+        // Asks for the top of `dir`, with `names` as the index pages, and
+        // returns whether it was found along with the log.
+        let serve = |names: &[&str]| {
+            let (mut c, _, log) =
+                Connection::in_memory(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n");
+            c.set_config(Rc::new(Config {
+                index_names: names
+                    .iter()
+                    .map(|n| n.as_bytes().to_vec())
+                    .collect(),
+                ..Config::default()
+            }));
+            let req = request::read(&mut c).unwrap();
+            let found = serve_request(&mut c, req, dir.as_bytes()).is_ok();
+            (found, String::from_utf8(log.contents()).unwrap())
+        };
+
+        let (found, log) = serve(&["index.html", "index.txt"]);
+        assert!(found);
+        assert!(!log.contains("index.html"));
+        assert!(log.contains("/index.txt [index]: success\n"));
+
+        let (found, log) = serve(&["index.html", "index.htm"]);
+        assert!(!found);
+        let log: Vec<_> = log.lines().collect();
+        assert_eq!(log.len(), 2);
+        assert!(log[0].ends_with("/index.html [index]: io not found"));
+        assert!(log[1].ends_with("/index.htm [index]: io not found"));
+    }
+
+    #[test]
+    fn test_serve_range() {
+        let out = exchange(
+            b"GET /main.rs HTTP/1.1\r\nHost: src\r\nRange: bytes=2-11\r\n\r\n",
+        );
+        let body = fs::read("src/main.rs").unwrap();
+
+        assert!(out.starts_with(b"HTTP/1.1 206 partial content\r\n"));
+        let range = format!("Content-Range: bytes 2-11/{}\r\n", body.len());
+        assert!(contains(&out, range.as_bytes()));
+        assert!(contains(&out, b"Content-Length: 10\r\n"));
+        assert!(contains(&out, b"Accept-Ranges: bytes\r\n"));
+        assert!(out.ends_with(&[&b"\r\n\r\n"[..], &body[2..12]].concat()));
+    }
+
+    #[test]
+    fn test_serve_multipart_range() {
+        let out = exchange(
+            b"GET /main.rs HTTP/1.1\r\nHost: src\r\n\
+              Range: bytes=0-4, -3\r\n\r\n",
+        );
+        let body = fs::read("src/main.rs").unwrap();
+        let len = body.len();
+
+        assert!(out.starts_with(b"HTTP/1.1 206 partial content\r\n"));
+        let split = out.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let (head, content) = (&out[..split + 2], &out[split + 4..]);
+        let head = String::from_utf8(head.to_vec()).unwrap();
+        let boundary = head
+            .split("\r\n")
+            .find_map(|h| {
+                h.strip_prefix("Content-Type: multipart/byteranges; boundary=")
+            })
+            .unwrap();
+        let length = format!("Content-Length: {}\r\n", content.len());
+        assert!(head.contains(&length));
+        assert!(!head.contains("Content-Range"));
+
+        let mut want = Vec::new();
+        for &(first, last) in &[(0, 4), (len - 3, len - 1)] {
+            want.extend_from_slice(
+                format!(
+                    "\r\n--{}\r\nContent-Type: text/plain\r\n\
+                     Content-Range: bytes {}-{}/{}\r\n\r\n",
+                    boundary, first, last, len
+                )
+                .as_bytes(),
+            );
+            want.extend_from_slice(&body[first..=last]);
+        }
+        want.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+        assert_eq!(content, &want[..]);
+    }
+
+    #[test]
+    fn test_serve_unsatisfiable_range() {
+        let out = exchange(
+            b"GET /main.rs HTTP/1.1\r\nHost: src\r\n\
+              Range: bytes=1000000-\r\n\r\n",
+        );
+        let body = fs::read("src/main.rs").unwrap();
+
+        assert!(out.starts_with(b"HTTP/1.1 416 range not satisfiable\r\n"));
+        let range = format!("Content-Range: bytes */{}\r\n", body.len());
+        assert!(contains(&out, range.as_bytes()));
+        assert!(out.ends_with(b"Content-Length: 0\r\n\r\n"));
+    }
+
+    #[test]
+    fn test_absolute_url() {
+        let (mut c, _, _) = Connection::in_memory(
+            b"GET /a HTTP/1.0\r\nHost: Example.com:8080\r\n\r\n",
+        );
+        let mut req = request::read(&mut c).unwrap();
+
+        assert_eq!(
+            absolute_url(&c, &req, b"/a/?q"),
+            b"http://Example.com:8080/a/?q".to_vec()
+        );
+        c.set_secure(true);
+        assert_eq!(
+            absolute_url(&c, &req, b"/a/"),
+            b"https://Example.com:8080/a/".to_vec()
+        );
+        req.host = None;
+        assert_eq!(absolute_url(&c, &req, b"/a/"), b"/a/".to_vec());
+    }
+
+    #[test]
+    fn test_scheme() {
+        // Works out the scheme for a request with `headers`, from a client at
+        // `remote`.
+        let scheme = |remote: &str, headers: &str, config: &Config| {
+            let input = format!("GET / HTTP/1.1\r\nHost: x\r\n{}\r\n", headers);
+            let mut c = Connection::new(
+                Box::new(io::Cursor::new(input.into_bytes())),
+                Box::new(SharedBuffer::default()),
+                Box::new(SharedBuffer::default()),
+                remote.to_string(),
+                Timeouts::default(),
+            );
+            c.set_config(Rc::new(config.clone()));
+            let req = request::read(&mut c).unwrap();
+            String::from_utf8(scheme(&c, &req).to_vec()).unwrap()
+        };
+        let plain = Config {
+            trusted_proxies: vec!["192.0.2.1".parse().unwrap()],
+            ..Config::default()
+        };
+        let https = Config {
+            https: true,
+            ..plain.clone()
+        };
+        let proxy = "192.0.2.1";
+        let other = "192.0.2.2";
+
+        // PROTO holds unless a trusted proxy says otherwise.
+        assert_eq!(scheme(proxy, "", &plain), "http");
+        assert_eq!(scheme(proxy, "", &https), "https");
+        assert_eq!(
+            scheme(other, "X-Forwarded-Proto: https\r\n", &plain),
+            "http"
+        );
+        assert_eq!(
+            scheme(other, "X-Forwarded-Proto: http\r\n", &https),
+            "https"
+        );
+        assert_eq!(
+            scheme(proxy, "X-Forwarded-Proto: https\r\n", &plain),
+            "https"
+        );
+        assert_eq!(
+            scheme(proxy, "X-Forwarded-Proto: http\r\n", &https),
+            "http"
+        );
+
+        // Only the last entry, across all the headers, is the proxy's own.
+        assert_eq!(
+            scheme(proxy, "X-Forwarded-Proto: https, http\r\n", &plain),
+            "http"
+        );
+        assert_eq!(
+            scheme(
+                proxy,
+                "X-Forwarded-Proto: https\r\nX-Forwarded-Proto: http\r\n",
+                &plain
+            ),
+            "http"
+        );
+        assert_eq!(
+            scheme(
+                proxy,
+                "X-Forwarded-Proto: http\r\nX-Forwarded-Proto: https,\r\n",
+                &plain
+            ),
+            "https"
+        );
+        // A value we don't understand tells us nothing.
+        assert_eq!(
+            scheme(proxy, "X-Forwarded-Proto: https, wss\r\n", &plain),
+            "http"
+        );
+        assert_eq!(
+            scheme(proxy, "X-Forwarded-Proto: http, wss\r\n", &https),
+            "https"
+        );
+    }
+
+    #[test]
+    fn test_host_root() {
+        let dir = TempDir::new("hosts");
+        for host in &["example.com", "0"] {
+            fs::create_dir(dir.path().join(host)).unwrap();
+        }
+        fs::write(
+            dir.path().join(".aliases"),
+            b"www.example.com example.com\nother.test missing.test\n",
+        )
+        .unwrap();
+
+        // Finds the directory for a request with `host`, along with the log.
+        let root = |host: &str, default: Option<&[u8]>| {
+            let input = format!("GET / HTTP/1.1\r\nHost: {}\r\n\r\n", host);
+            let (mut c, _, log) = Connection::in_memory(input.as_bytes());
+            c.set_config(Rc::new(Config {
+                default_host: default.map(<[u8]>::to_vec),
+                ..Config::default()
+            }));
+            let req = request::read(&mut c).unwrap();
+            let root = host_root(&mut c, &req, dir.as_bytes()).map(|root| {
+                String::from_utf8(root[dir.as_bytes().len()..].to_vec())
+                    .unwrap()
+            });
+            let vhost = c.record().vhost.take();
+            (root, vhost, String::from_utf8(log.contents()).unwrap())
+        };
+
+        let (found, vhost, log) = root("Example.COM", None);
+        assert_eq!(found.as_deref(), Some("/example.com"));
+        assert_eq!(vhost.as_deref(), Some(&b"example.com"[..]));
+        assert_eq!(log, "");
+
+        let (found, vhost, log) = root("www.example.com:443", None);
+        assert_eq!(found.as_deref(), Some("/example.com"));
+        assert_eq!(vhost.as_deref(), Some(&b"example.com"[..]));
+        assert_eq!(
+            log,
+            "REMOTE note: host www.example.com is an alias of example.com\n"
+        );
+
+        // Without DEFAULT_HOST, unknown hosts get directories that don't
+        // exist, and then 404s.
+        let (found, _, _) = root("unknown.test", None);
+        assert_eq!(found.as_deref(), Some("/unknown.test"));
+
+        let (found, vhost, log) = root("unknown.test", Some(b"0"));
+        assert_eq!(found.as_deref(), Some("/0"));
+        assert_eq!(vhost.as_deref(), Some(&b"0"[..]));
+        assert_eq!(log, "REMOTE note: unknown host unknown.test; using 0\n");
+
+        // An alias for a host with no directory leads to the default too.
+        let (found, _, log) = root("other.test", Some(b"0"));
+        assert_eq!(found.as_deref(), Some("/0"));
+        assert!(log.ends_with("unknown host missing.test; using 0\n"));
+
+        let (found, _, _) = root("../etc", Some(b"0"));
+        assert_eq!(found, None);
+    }
+
+    #[test]
+    fn test_serve_requires_host() {
+        let out = exchange(b"GET /main.rs HTTP/1.1\r\n\r\n");
+        assert!(out.starts_with(b"HTTP/1.1 400 "));
+    }
+
+    #[test]
+    fn test_normalize_host() {
+        let normalize =
+            |host: &[u8]| normalize_host(host).map(String::from_utf8);
+        let good = |host: &str| Some(Ok(host.to_string()));
+
+        assert_eq!(normalize(b"Example.COM"), good("example.com"));
+        assert_eq!(normalize(b"example.com.:8080"), good("example.com"));
+        assert_eq!(
+            normalize(b"xn--bcher-kva.example:"),
+            good("xn--bcher-kva.example")
+        );
+        assert_eq!(normalize(b"192.0.2.1:80"), good("192.0.2.1"));
+        assert_eq!(normalize(b"[::1]:8080"), good("[::1]"));
+        assert_eq!(normalize(b"[2001:DB8:0:0::1]"), good("[2001:db8::1]"));
+
+        for bad in &[
+            &b""[..],
+            b"..",
+            b"../etc",
+            b"foo/bar",
+            b".hidden",
+            b"a..b",
+            b"exa mple.com",
+            b"b\xc3\xbccher.example",
+            b"host:port",
+            b"host:80:80",
+            b"[::1",
+            b"[::1]x",
+            b"[example.com]",
+            b"[fe80::1%25eth0]",
+        ] {
+            assert_eq!(normalize(bad), None);
         }
     }
 }
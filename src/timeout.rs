@@ -1,39 +1,76 @@
 //! IO operations with timeout support.
-use libc::time_t;
+use libc::{suseconds_t, time_t};
 use std::fs;
 use std::io;
+use std::time::{Duration, Instant};
 
+use std::os::unix::fs::FileTypeExt;
 use std::os::unix::io::AsRawFd;
 
+use crate::con::{Input, Output};
+
 fn cvt_err(e: nix::Error) -> io::Error {
-    io::Error::new(io::ErrorKind::Other, format!("{}", e))
+    match e.as_errno() {
+        Some(errno) => errno.into(),
+        None => io::Error::other(format!("{}", e)),
+    }
+}
+
+/// The limits on how long we'll wait for a client during various phases of a
+/// connection.
+#[derive(Debug, Copy, Clone)]
+pub struct Timeouts {
+    /// Longest wait for any single read while receiving a request.
+    pub read: Duration,
+    /// Longest time between the start of a request and the end of its headers,
+    /// however quickly the individual pieces arrive.
+    pub request: Duration,
+    /// Longest wait for a persistent connection's next request to start.
+    pub idle: Duration,
+    /// Longest wait for any single write.
+    pub write: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        let minute = Duration::from_secs(60);
+        Timeouts {
+            read: minute,
+            request: minute,
+            idle: minute,
+            write: minute,
+        }
+    }
+}
+
+fn timeval(d: Duration) -> nix::sys::time::TimeVal {
+    nix::sys::time::TimeVal::from(libc::timeval {
+        tv_sec: d.as_secs() as time_t,
+        tv_usec: d.subsec_micros() as suseconds_t,
+    })
 }
 
 /// A trait for objects that can produce data, but not all the time.  This trait
 /// would typically be combined with `std::io::Read`.
 trait ReadTimeout {
     /// Waits until at least some data is available from this object, up to the
-    /// specified number of seconds.  If time elapses, this returns an error.
-    fn wait_for_data(&mut self, seconds: u32) -> io::Result<()>;
+    /// specified time.  If time elapses, this returns an error.
+    fn wait_for_data(&mut self, timeout: Duration) -> io::Result<()>;
 }
 
 /// A trait for objects that can consume data, but not all the time.  This trait
 /// would typically be combined with `std::io::Write`.
 trait WriteTimeout {
     /// Waits until at least some data can be written to this object, up to the
-    /// specified number of seconds.  If time elapses, this returns an error.
-    fn wait_for_writeable(&mut self, seconds: u32) -> io::Result<()>;
+    /// specified time.  If time elapses, this returns an error.
+    fn wait_for_writeable(&mut self, timeout: Duration) -> io::Result<()>;
 }
 
 impl ReadTimeout for fs::File {
-    fn wait_for_data(&mut self, seconds: u32) -> io::Result<()> {
+    fn wait_for_data(&mut self, timeout: Duration) -> io::Result<()> {
         use nix::sys::select::{select, FdSet};
-        use nix::sys::time::TimeVal;
 
-        let mut tv = TimeVal::from(libc::timeval {
-            tv_sec: seconds as time_t,
-            tv_usec: 0,
-        });
+        let mut tv = timeval(timeout);
 
         let fd = self.as_raw_fd();
         let mut fds = FdSet::new();
@@ -52,20 +89,16 @@ impl<T> ReadTimeout for io::BufReader<T>
 where
     T: ReadTimeout + io::Read,
 {
-    fn wait_for_data(&mut self, seconds: u32) -> io::Result<()> {
-        self.get_mut().wait_for_data(seconds)
+    fn wait_for_data(&mut self, timeout: Duration) -> io::Result<()> {
+        self.get_mut().wait_for_data(timeout)
     }
 }
 
 impl WriteTimeout for fs::File {
-    fn wait_for_writeable(&mut self, seconds: u32) -> io::Result<()> {
+    fn wait_for_writeable(&mut self, timeout: Duration) -> io::Result<()> {
         use nix::sys::select::{select, FdSet};
-        use nix::sys::time::TimeVal;
 
-        let mut tv = TimeVal::from(libc::timeval {
-            tv_sec: seconds as time_t,
-            tv_usec: 0,
-        });
+        let mut tv = timeval(timeout);
 
         let fd = self.as_raw_fd();
         let mut fds = FdSet::new();
@@ -84,38 +117,174 @@ impl<T> WriteTimeout for io::BufWriter<T>
 where
     T: WriteTimeout + io::Write,
 {
-    fn wait_for_writeable(&mut self, seconds: u32) -> io::Result<()> {
-        self.get_mut().wait_for_writeable(seconds)
+    fn wait_for_writeable(&mut self, timeout: Duration) -> io::Result<()> {
+        self.get_mut().wait_for_writeable(timeout)
     }
 }
 
-/// A wrapper for `File` that ensures that all read operations are done under
-/// a (fixed) timeout.
-pub struct SafeFile(fs::File);
+/// A wrapper for `File` that ensures that all read and write operations are
+/// done under a timeout.  Reads can additionally be subject to a deadline.
+pub struct SafeFile {
+    file: fs::File,
+    timeout: Duration,
+    deadline: Option<Instant>,
+}
 
 impl SafeFile {
-    pub fn new(inner: fs::File) -> Self {
-        SafeFile(inner)
+    pub fn new(inner: fs::File, timeout: Duration) -> Self {
+        SafeFile {
+            file: inner,
+            timeout,
+            deadline: None,
+        }
+    }
+
+    /// Works out how long the next read may wait.
+    fn read_timeout(&self) -> io::Result<Duration> {
+        match self.deadline {
+            None => Ok(self.timeout),
+            Some(d) => match d.checked_duration_since(Instant::now()) {
+                Some(left) if left > Duration::from_secs(0) => {
+                    Ok(left.min(self.timeout))
+                }
+                _ => Err(nix::errno::Errno::ETIMEDOUT.into()),
+            },
+        }
+    }
+}
+
+impl Input for SafeFile {
+    fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
+    }
+}
+
+impl Output for SafeFile {
+    /// Copying straight from files only pays off, and is only reliably
+    /// supported, when we're talking to a socket.
+    fn can_send_file(&self) -> bool {
+        cfg!(any(target_os = "android", target_os = "linux"))
+            && self
+                .file
+                .metadata()
+                .map(|m| m.file_type().is_socket())
+                .unwrap_or(false)
+    }
+
+    /// Uses `sendfile(2)`, under the same timeout as `write`.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn send_file(
+        &mut self,
+        src: &fs::File,
+        offset: &mut u64,
+        count: usize,
+    ) -> io::Result<usize> {
+        use nix::errno::Errno;
+        use nix::fcntl::{fcntl, FcntlArg, OFlag};
+        use nix::sys::sendfile::sendfile;
+
+        // A blocking sendfile can sleep until it's copied everything, which
+        // would let a stalled client hold us indefinitely.  Make the output
+        // non-blocking for the duration, so that waiting only happens under
+        // our timeout.
+        let fd = self.file.as_raw_fd();
+        let flags = OFlag::from_bits_truncate(
+            fcntl(fd, FcntlArg::F_GETFL).map_err(cvt_err)?,
+        );
+        fcntl(fd, FcntlArg::F_SETFL(flags | OFlag::O_NONBLOCK))
+            .map_err(cvt_err)?;
+
+        let result = loop {
+            if let Err(e) = self.file.wait_for_writeable(self.timeout) {
+                break Err(e);
+            }
+            let mut off = *offset as libc::off_t;
+            match sendfile(fd, src.as_raw_fd(), Some(&mut off), count) {
+                Err(nix::Error::Sys(Errno::EAGAIN)) => continue,
+                Err(e) => break Err(cvt_err(e)),
+                Ok(n) => {
+                    *offset = off as u64;
+                    break Ok(n);
+                }
+            }
+        };
+
+        fcntl(fd, FcntlArg::F_SETFL(flags)).map_err(cvt_err)?;
+        result
     }
 }
 
 impl io::Read for SafeFile {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        self.0.wait_for_data(60).and_then(|_| self.0.read(buf))
+        let timeout = self.read_timeout()?;
+        self.file
+            .wait_for_data(timeout)
+            .and_then(|_| self.file.read(buf))
     }
 }
 
 impl io::Write for SafeFile {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.0
-            .wait_for_writeable(60)
-            .and_then(|_| self.0.write(buf))
+        self.file
+            .wait_for_writeable(self.timeout)
+            .and_then(|_| self.file.write(buf))
     }
 
     fn flush(&mut self) -> io::Result<()> {
         // On Unix, at least, flushing a raw File is a no-op -- so no timeout
         // is required here.  Flushing a buffered writer will hit the write
         // timeout, above.
-        self.0.flush()
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::os::unix::io::FromRawFd;
+
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    #[test]
+    fn test_send_file() {
+        // sendfile will write to a pipe as happily as to a socket, and a pipe
+        // can be made small enough that the source doesn't fit in it.
+        let (theirs, ours) = nix::unistd::pipe().unwrap();
+        assert!(unsafe { libc::fcntl(ours, libc::F_SETPIPE_SZ, 4096) } > 0);
+        let mut theirs = unsafe { fs::File::from_raw_fd(theirs) };
+        let ours = unsafe { fs::File::from_raw_fd(ours) };
+        let mut out = SafeFile::new(ours, Duration::from_millis(50));
+        assert!(!out.can_send_file());
+
+        let src = fs::File::open("src/http2.rs").unwrap();
+        let expected = fs::read("src/http2.rs").unwrap();
+
+        // With nobody reading, we get as much in as fits...
+        let mut offset = 10;
+        let sent = out.send_file(&src, &mut offset, expected.len()).unwrap();
+        assert!(sent > 0 && sent < expected.len() - 10);
+        assert_eq!(offset, 10 + sent as u64);
+
+        // ...and then time out rather than wait for room for the rest.
+        let start = Instant::now();
+        let err = out
+            .send_file(&src, &mut offset, expected.len())
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ETIMEDOUT));
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert_eq!(offset, 10 + sent as u64);
+
+        // Either way, the output is left blocking, as we found it.
+        let flags = unsafe { libc::fcntl(out.file.as_raw_fd(), libc::F_GETFL) };
+        assert_eq!(flags & libc::O_NONBLOCK, 0);
+
+        drop(out);
+        let mut received = Vec::new();
+        theirs.read_to_end(&mut received).unwrap();
+        assert_eq!(received, &expected[10..10 + sent]);
     }
 }
@@ -0,0 +1,410 @@
+//! TLS termination, for serving HTTPS without a separate proxy.
+//!
+//! Certificates live in a directory laid out like the document root: each
+//! virtual host gets a subdirectory, named as `server::normalize_host` would
+//! name its documents, containing `cert.pem` (the certificate chain, leaf
+//! first) and `key.pem` (the private key).  The host a client asks for with
+//! SNI is resolved to a virtual host just as its requests will be, following
+//! aliases and falling back on `DEFAULT_HOST`.  Clients that don't use SNI
+//! are treated like HTTP/1.0 requests without a host.  Any that end up
+//! somewhere without a certificate get the one in `0`, if there is one.
+//!
+//! The certificates are loaded before we give up our privileges, so neither
+//! they nor the keys need to be readable by the serving user, or visible in
+//! the document root.  The document root's hosts and aliases are noted at the
+//! same time, so that choosing a certificate doesn't touch the filesystem.
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use rustls::crypto::ring;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+
+use crate::aliases::{self, Alias};
+use crate::con::{Connection, Input, Output};
+use crate::server::{normalize_host, resolve_host, Hosts};
+use crate::timeout::{SafeFile, Timeouts};
+
+/// Loads the certificates in `dir` and builds a server configuration that
+/// chooses between them for the hosts in `docroot`.  `default_host` serves
+/// hosts without directories.
+pub fn load(
+    dir: &Path,
+    docroot: &Path,
+    default_host: Option<&[u8]>,
+) -> io::Result<Arc<ServerConfig>> {
+    let certificates = Certificates::load(dir, docroot, default_host)?;
+    let mut config =
+        ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(io::Error::other)?
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(certificates));
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(Arc::new(config))
+}
+
+/// Creates a connection that speaks TLS over `input` and `output`, which are
+/// normally the two ends of a single socket, and logs to `error`.
+pub fn connection(
+    config: Arc<ServerConfig>,
+    input: fs::File,
+    output: fs::File,
+    error: Box<dyn Write>,
+    remote: String,
+    timeouts: Timeouts,
+) -> io::Result<Connection> {
+    let transport = Transport {
+        input: SafeFile::new(input, timeouts.read),
+        output: SafeFile::new(output, timeouts.write),
+    };
+    let tls = ServerConnection::new(config).map_err(io::Error::other)?;
+    let stream = Rc::new(RefCell::new(StreamOwned::new(tls, transport)));
+
+    Ok(Connection::new(
+        Box::new(TlsInput(stream.clone())),
+        Box::new(TlsOutput(stream)),
+        error,
+        remote,
+        timeouts,
+    ))
+}
+
+/// The certificates we can offer, by normalized host name.
+#[derive(Debug)]
+struct Certificates {
+    by_host: HashMap<Vec<u8>, Arc<CertifiedKey>>,
+    /// The document root's hosts, to resolve names against.
+    hosts: HostList,
+    /// The host to resolve unknown hosts to, as in `Config`.
+    default_host: Option<Vec<u8>>,
+}
+
+impl Certificates {
+    fn load(
+        dir: &Path,
+        docroot: &Path,
+        default_host: Option<&[u8]>,
+    ) -> io::Result<Certificates> {
+        let mut by_host = HashMap::new();
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            // As in the document root, dotfiles aren't hosts, and neither is
+            // anything a client couldn't ask for.
+            if name.to_string_lossy().starts_with('.')
+                || !entry.file_type()?.is_dir()
+            {
+                continue;
+            }
+
+            let host = match normalize_host(name.to_string_lossy().as_bytes()) {
+                Some(host) => host,
+                None => continue,
+            };
+            let key = load_host(&entry.path())?;
+            by_host.insert(host, Arc::new(key));
+        }
+
+        if by_host.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "no certificates",
+            ));
+        }
+        Ok(Certificates {
+            by_host,
+            hosts: HostList::read(docroot)?,
+            default_host: default_host.map(<[u8]>::to_vec),
+        })
+    }
+
+    /// Picks the certificate for the host named by SNI, if any.
+    fn lookup(&self, server_name: Option<&str>) -> Option<Arc<CertifiedKey>> {
+        let host = server_name
+            .and_then(|name| normalize_host(name.as_bytes()))
+            .unwrap_or_else(|| b"0".to_vec());
+        let default = self.default_host.as_deref();
+        let name = resolve_host(&self.hosts, &host, default, |_| ());
+        self.by_host
+            .get(&name)
+            .or_else(|| self.by_host.get(&b"0"[..]))
+            .cloned()
+    }
+}
+
+/// The hosts in a document root, as they were when we looked.
+#[derive(Debug)]
+struct HostList {
+    aliases: Vec<Alias>,
+    dirs: HashSet<Vec<u8>>,
+}
+
+impl HostList {
+    fn read(docroot: &Path) -> io::Result<HostList> {
+        let mut dirs = HashSet::new();
+        for entry in fs::read_dir(docroot)? {
+            let entry = entry?;
+            if entry.path().is_dir() {
+                dirs.insert(entry.file_name().as_bytes().to_vec());
+            }
+        }
+        Ok(HostList {
+            aliases: aliases::read(docroot.as_os_str().as_bytes()),
+            dirs,
+        })
+    }
+}
+
+impl Hosts for HostList {
+    fn alias_of(&self, host: &[u8]) -> Option<Vec<u8>> {
+        aliases::find(&self.aliases, host)
+    }
+
+    fn has_dir(&self, host: &[u8]) -> bool {
+        self.dirs.contains(host)
+    }
+}
+
+impl ResolvesServerCert for Certificates {
+    fn resolve(&self, hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.lookup(hello.server_name())
+    }
+}
+
+fn load_host(dir: &Path) -> io::Result<CertifiedKey> {
+    let invalid = |e| io::Error::new(io::ErrorKind::InvalidData, e);
+
+    let chain = CertificateDer::pem_file_iter(dir.join("cert.pem"))
+        .map_err(invalid)?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(invalid)?;
+    let key =
+        PrivateKeyDer::from_pem_file(dir.join("key.pem")).map_err(invalid)?;
+    let signer =
+        ring::sign::any_supported_type(&key).map_err(io::Error::other)?;
+
+    Ok(CertifiedKey::new(chain, signer))
+}
+
+/// The encrypted side of a connection.
+struct Transport {
+    input: SafeFile,
+    output: SafeFile,
+}
+
+impl Read for Transport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.input.read(buf)
+    }
+}
+
+impl Write for Transport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.output.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+}
+
+type Stream = StreamOwned<ServerConnection, Transport>;
+
+/// The reading half of a TLS connection.  Reads may also write, to complete a
+/// handshake.
+struct TlsInput(Rc<RefCell<Stream>>);
+
+impl Read for TlsInput {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.0.borrow_mut().read(buf) {
+            // A client that hangs up without saying goodbye could in theory be
+            // the victim of a truncation attack -- but a truncated request is
+            // an incomplete one, which we reject anyway.
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(0),
+            result => result,
+        }
+    }
+}
+
+impl Input for TlsInput {
+    fn set_timeout(&mut self, timeout: Duration) {
+        self.0.borrow_mut().sock.input.set_timeout(timeout);
+    }
+
+    fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.0.borrow_mut().sock.input.set_deadline(deadline);
+    }
+}
+
+/// The writing half of a TLS connection.  File bodies have to be encrypted,
+/// so they can't bypass us.
+struct TlsOutput(Rc<RefCell<Stream>>);
+
+impl Write for TlsOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.borrow_mut().flush()
+    }
+}
+
+impl Output for TlsOutput {}
+
+impl Drop for TlsOutput {
+    fn drop(&mut self) {
+        // Tell the client we're done on purpose, so it can trust that it has
+        // the whole response.  If it's gone, there's nobody to tell.
+        let mut stream = self.0.borrow_mut();
+        stream.conn.send_close_notify();
+        let _ = stream.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::con::SharedBuffer;
+    use crate::file::TempDir;
+    use std::convert::TryFrom;
+    use std::os::unix::io::OwnedFd;
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+
+    use rustls::pki_types::ServerName;
+    use rustls::{ClientConfig, ClientConnection, RootCertStore};
+
+    /// Creates a certificate directory holding self-signed certificates for
+    /// each of `hosts`, returning it along with the certificates.
+    fn make_cert_dir(
+        tag: &str,
+        hosts: &[&str],
+    ) -> (TempDir, Vec<CertificateDer<'static>>) {
+        let dir = TempDir::new(&format!("tls-{}", tag));
+
+        let mut certs = Vec::new();
+        for host in hosts {
+            let cert =
+                rcgen::generate_simple_self_signed(vec![host.to_string()])
+                    .unwrap();
+            let host_dir = dir.path().join(host);
+            fs::create_dir_all(&host_dir).unwrap();
+            fs::write(host_dir.join("cert.pem"), cert.cert.pem()).unwrap();
+            fs::write(host_dir.join("key.pem"), cert.key_pair.serialize_pem())
+                .unwrap();
+            certs.push(cert.cert.der().clone());
+        }
+        (dir, certs)
+    }
+
+    #[test]
+    fn test_lookup() {
+        let (dir, certs) =
+            make_cert_dir("lookup", &["example.com", "0", "other.test"]);
+        fs::create_dir(dir.path().join(".hidden")).unwrap();
+        let docroot = TempDir::new("tls-lookup-docroot");
+        fs::create_dir(docroot.path().join("example.com")).unwrap();
+        fs::create_dir(docroot.path().join("known.test")).unwrap();
+        fs::write(
+            docroot.path().join(".aliases"),
+            b"www.example.com example.com\n",
+        )
+        .unwrap();
+
+        let c = Certificates::load(dir.path(), docroot.path(), None).unwrap();
+        let served = |name| c.lookup(name).unwrap().cert[0].clone();
+        assert_eq!(served(Some("EXAMPLE.com")), certs[0]);
+        assert_eq!(served(Some("www.example.com")), certs[0]);
+        assert_eq!(served(Some("other.test")), certs[2]);
+        assert_eq!(served(Some("unknown.test")), certs[1]);
+        assert_eq!(served(None), certs[1]);
+
+        // Hosts without directories are resolved like requests for them,
+        // against the document root as it was when the certificates were
+        // loaded.
+        let default = Some(&b"other.test"[..]);
+        let c =
+            Certificates::load(dir.path(), docroot.path(), default).unwrap();
+        fs::create_dir(docroot.path().join("late.test")).unwrap();
+        let served = |name| c.lookup(name).unwrap().cert[0].clone();
+        assert_eq!(served(Some("www.example.com")), certs[0]);
+        assert_eq!(served(Some("unknown.test")), certs[2]);
+        assert_eq!(served(Some("late.test")), certs[2]);
+        assert_eq!(served(None), certs[2]);
+        assert_eq!(served(Some("known.test")), certs[1]);
+    }
+
+    #[test]
+    fn test_load_failures() {
+        let (dir, _) = make_cert_dir("failures", &[]);
+        let docroot = TempDir::new("tls-failures-docroot");
+        assert!(load(dir.path(), docroot.path(), None).is_err());
+
+        fs::create_dir(dir.path().join("nokey")).unwrap();
+        assert!(load(dir.path(), docroot.path(), None).is_err());
+    }
+
+    #[test]
+    fn test_https_exchange() {
+        let (dir, certs) = make_cert_dir("exchange", &["localhost"]);
+        // The exchange itself is served from the top of the source tree.
+        let config = load(dir.path(), Path::new("."), None).unwrap();
+
+        let (server_end, client_end) = UnixStream::pair().unwrap();
+        let server = thread::spawn(move || {
+            let output = server_end.try_clone().unwrap();
+            let error = SharedBuffer::default();
+            let c = connection(
+                config,
+                fs::File::from(OwnedFd::from(server_end)),
+                fs::File::from(OwnedFd::from(output)),
+                Box::new(error.clone()),
+                "REMOTE".to_string(),
+                Timeouts::default(),
+            )
+            .unwrap();
+            crate::server::serve(c).unwrap();
+            error.contents()
+        });
+
+        let mut roots = RootCertStore::empty();
+        roots.add(certs[0].clone()).unwrap();
+        let client_config = ClientConfig::builder_with_provider(Arc::new(
+            ring::default_provider(),
+        ))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+        let client = ClientConnection::new(
+            Arc::new(client_config),
+            ServerName::try_from("localhost").unwrap(),
+        )
+        .unwrap();
+        let mut stream = StreamOwned::new(client, client_end);
+
+        stream
+            .write_all(b"GET /main.rs HTTP/1.0\r\nHost: src\r\n\r\n")
+            .unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+        let log = server.join().unwrap();
+
+        assert!(response.starts_with(b"HTTP/1.0 200 OK\r\n"));
+        assert!(log.starts_with(b"REMOTE read ./src/main.rs"));
+        assert!(response.ends_with(&fs::read("src/main.rs").unwrap()));
+    }
+}
@@ -26,20 +26,3 @@ pub fn stderr() -> fs::File {
     std::mem::forget(s);
     unsafe { fs::File::from_raw_fd(fd) }
 }
-
-/// Wraps POSIX pipe(2).  On success, returns a pair of Files that own the
-/// pipe's file descriptors.
-#[cfg(test)]
-pub fn pipe() -> nix::Result<Pipe> {
-    let (fd0, fd1) = nix::unistd::pipe()?;
-    Ok(Pipe {
-        input: unsafe { fs::File::from_raw_fd(fd0) },
-        output: unsafe { fs::File::from_raw_fd(fd1) },
-    })
-}
-
-#[cfg(test)]
-pub struct Pipe {
-    pub input: fs::File,
-    pub output: fs::File,
-}